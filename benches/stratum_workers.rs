@@ -0,0 +1,255 @@
+// Measures stratum share submission throughput as clients are spread across more worker threads,
+// the same way the proxy shards its clients (one StratumServer per worker, each with its own event
+// loop, with solutions funneled back over a shared channel).
+//
+// Only client-facing work is sharded. Job providers, pools and share forwarding all stay on the
+// proxy's main thread, so "submit" shows how the workers scale while "submit_with_upstream" also
+// turns every solution into an encoded pool Share on a single thread, as the main thread does, to
+// show where that single-threaded stage caps throughput.
+
+extern crate bitcoin;
+extern crate bytes;
+extern crate futures;
+extern crate tokio;
+extern crate tokio_io;
+extern crate tokio_codec;
+extern crate tokio_timer;
+extern crate crypto;
+extern crate secp256k1;
+extern crate tokio_uds;
+
+#[macro_use]
+extern crate serde_json;
+
+#[macro_use]
+extern crate criterion;
+
+#[allow(dead_code)]
+#[path = "../src/auth.rs"]
+mod auth;
+#[allow(dead_code)]
+#[path = "../src/limits.rs"]
+mod limits;
+#[allow(dead_code)]
+#[path = "../src/msg_framing.rs"]
+mod msg_framing;
+#[allow(dead_code)]
+#[path = "../src/shares.rs"]
+mod shares;
+#[allow(dead_code)]
+#[path = "../src/stratum_server.rs"]
+mod stratum_server;
+#[allow(dead_code)]
+#[path = "../src/utils.rs"]
+mod utils;
+#[allow(dead_code)]
+#[path = "../src/vardiff.rs"]
+mod vardiff;
+
+use auth::AllowAllWorkers;
+use limits::{ClientLimiter,LimitsConfig};
+use msg_framing::{BlockTemplate,PoolMessage,PoolMsgFramer,PoolShare,WinningNonce,WorkInfo};
+use shares::{DuplicateShareCounts,JobRetention};
+use stratum_server::{StratumConfig,StratumServer,TransactionsFormat};
+use vardiff::VardiffConfig;
+
+use bitcoin::util::hash::Sha256dHash;
+
+use criterion::{Criterion,ParameterizedBenchmark,Throughput};
+
+use futures::{future,Future};
+use futures::sync::{mpsc,oneshot};
+use futures::{Sink,Stream};
+
+use tokio::executor::current_thread;
+
+use tokio_io::codec::Encoder;
+
+use tokio_timer::Timer;
+
+use std::io::{BufRead,BufReader,Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread;
+use std::time::Duration;
+
+const CLIENTS_PER_WORKER: usize = 4;
+/// Total shares submitted per iteration, split evenly across all clients
+const SUBMITS_PER_ITER: usize = 1024;
+/// How many submits each client has outstanding at once. This must stay below the server's
+/// per-client send buffer, as responses which don't fit are dropped.
+const PIPELINE_DEPTH: usize = 4;
+
+struct Client {
+	stream: UnixStream,
+	reader: BufReader<UnixStream>,
+}
+
+impl Client {
+	fn read_line(&mut self) -> String {
+		let mut line = String::new();
+		self.reader.read_line(&mut line).unwrap();
+		line
+	}
+}
+
+fn test_template() -> BlockTemplate {
+	BlockTemplate {
+		template_id: 42,
+		// Accept every share so that each submit goes all the way through to the solutions channel
+		target: [0xff; 32],
+
+		header_version: 0x20000000,
+		header_prevblock: [0x11; 32],
+		header_time: 1_500_000_000,
+		header_nbits: 0x1d00ffff,
+
+		// Roughly a full block's worth of merkle branches
+		merkle_rhss: vec![[0x22; 32]; 12],
+		coinbase_value_remaining: 1_250_000_000,
+
+		coinbase_version: 1,
+		coinbase_prefix: vec![0x03, 0x01, 0x02, 0x03],
+		coinbase_postfix: Vec::new(),
+		coinbase_input_sequence: 0xffffffff,
+		appended_coinbase_outputs: Vec::new(),
+		coinbase_locktime: 0,
+	}
+}
+
+/// Starts worker_count workers, each serving CLIENTS_PER_WORKER subscribed clients on the test job.
+/// Solutions are handled on a single thread, which encodes each into a pool Share if upstream is
+/// set. Returns the clients and a count of solutions handled so far.
+fn start_workers(worker_count: usize, upstream: bool) -> (Vec<Client>, Arc<AtomicUsize>) {
+	let template = Arc::new(test_template());
+	let (solution_tx, solution_rx) = mpsc::unbounded::<Arc<(WinningNonce, Sha256dHash)>>();
+	let solutions_handled = Arc::new(AtomicUsize::new(0));
+	let solutions_handled_ref = solutions_handled.clone();
+	let template_ref = template.clone();
+	thread::spawn(move || {
+		let mut framer = PoolMsgFramer::new();
+		let mut buf = bytes::BytesMut::new();
+		for solution in solution_rx.wait() {
+			if upstream {
+				let solution = solution.unwrap();
+				framer.encode(PoolMessage::Share {
+					share: PoolShare {
+						share_id: None,
+						header_version: solution.0.header_version,
+						header_prevblock: template_ref.header_prevblock,
+						header_time: solution.0.header_time,
+						header_nbits: template_ref.header_nbits,
+						header_nonce: solution.0.header_nonce,
+						merkle_rhss: template_ref.merkle_rhss.clone(),
+						coinbase_tx: solution.0.coinbase_tx.clone(),
+						user_tag: solution.0.user_tag.clone(),
+					}
+				}, &mut buf).unwrap();
+				buf.clear();
+			}
+			solutions_handled_ref.fetch_add(1, Ordering::AcqRel);
+		}
+	});
+
+	// We're measuring how fast we can process shares, so don't rate-limit them
+	let limiter = ClientLimiter::new(LimitsConfig {
+		max_msgs_per_sec: u32::max_value(),
+		..Default::default()
+	});
+
+	let config = StratumConfig {
+		job_retention: JobRetention::default(),
+		renotify_interval: Duration::from_secs(30),
+		vardiff: VardiffConfig::default(),
+		worker_verifier: Arc::new(AllowAllWorkers),
+		transactions_format: TransactionsFormat::Txids,
+	};
+	let duplicate_shares = DuplicateShareCounts::new();
+	let timer = Timer::default();
+	let mut clients = Vec::new();
+	for worker_id in 0..worker_count {
+		let (mut job_tx, job_rx) = mpsc::channel(5);
+		let (conn_tx, conn_rx) = mpsc::unbounded();
+		let config = config.clone();
+		let duplicate_shares = duplicate_shares.clone();
+		let timer = timer.clone();
+		thread::spawn(move || {
+			current_thread::block_on_all(future::lazy(move || -> future::FutureResult<(), ()> {
+				let server = StratumServer::new(job_rx, worker_id as u16, Arc::new(AtomicUsize::new(0)), config, duplicate_shares, timer);
+				current_thread::spawn(conn_rx.for_each(move |(sock, slot)| {
+					let sock = tokio_uds::UnixStream::from_std(sock, &tokio::reactor::Handle::default()).unwrap();
+					StratumServer::new_connection(server.clone(), sock, slot);
+					future::result(Ok(()))
+				}));
+				future::result(Ok(()))
+			})).unwrap();
+		});
+
+		for _ in 0..CLIENTS_PER_WORKER {
+			let (ours, theirs) = UnixStream::pair().unwrap();
+			conn_tx.unbounded_send((theirs, ClientLimiter::try_accept(&limiter, None).unwrap())).unwrap();
+			let mut client = Client { reader: BufReader::new(ours.try_clone().unwrap()), stream: ours };
+			client.stream.write_all(b"{\"id\":1,\"method\":\"mining.subscribe\",\"params\":[]}\n").unwrap();
+			client.read_line();
+			client.stream.write_all(b"{\"id\":2,\"method\":\"mining.authorize\",\"params\":[\"bench\",\"x\"]}\n").unwrap();
+			client.read_line();
+			clients.push(client);
+		}
+
+		let (_, transactions) = oneshot::channel();
+		job_tx.start_send(WorkInfo { template: template.clone(), solutions: solution_tx.clone(), transactions: transactions.shared() }).unwrap();
+		job_tx.poll_complete().unwrap();
+		// The job sender is kept around forever so that the worker never stops
+		std::mem::forget(job_tx);
+	}
+
+	for client in clients.iter_mut() {
+		// mining.set_difficulty and mining.notify
+		client.read_line();
+		client.read_line();
+	}
+	(clients, solutions_handled)
+}
+
+/// Submits SUBMITS_PER_ITER shares across all clients, waiting for their responses and, if
+/// wait_upstream is set, for every solution to have been handled
+fn submit_shares(clients: &mut Vec<Client>, solutions_handled: &AtomicUsize, nonce: &mut u32, wait_upstream: bool) {
+	let submits_per_client = SUBMITS_PER_ITER / clients.len();
+	let target_handled = solutions_handled.load(Ordering::Acquire) + submits_per_client * clients.len();
+	for _ in 0..submits_per_client / PIPELINE_DEPTH {
+		for client in clients.iter_mut() {
+			let mut batch = String::new();
+			for _ in 0..PIPELINE_DEPTH {
+				*nonce = nonce.wrapping_add(1);
+				batch.push_str(&format!("{{\"id\":2,\"method\":\"mining.submit\",\"params\":[\"bench\",\"42\",\"0000000000000000\",\"59682f00\",\"{:08x}\"]}}\n", nonce));
+			}
+			client.stream.write_all(batch.as_bytes()).unwrap();
+		}
+		for client in clients.iter_mut() {
+			for _ in 0..PIPELINE_DEPTH {
+				assert!(client.read_line().contains("\"result\":true"));
+			}
+		}
+	}
+	if wait_upstream {
+		while solutions_handled.load(Ordering::Acquire) < target_handled {
+			thread::yield_now();
+		}
+	}
+}
+
+fn bench_submits(c: &mut Criterion) {
+	c.bench("stratum_workers", ParameterizedBenchmark::new("submit", |b, &worker_count| {
+		let (mut clients, solutions_handled) = start_workers(worker_count, false);
+		let mut nonce: u32 = 0;
+		b.iter(|| submit_shares(&mut clients, &solutions_handled, &mut nonce, false));
+	}, vec![1, 2, 4]).with_function("submit_with_upstream", |b, &worker_count| {
+		let (mut clients, solutions_handled) = start_workers(worker_count, true);
+		let mut nonce: u32 = 0;
+		b.iter(|| submit_shares(&mut clients, &solutions_handled, &mut nonce, true));
+	}).throughput(|_| Throughput::Elements(SUBMITS_PER_ITER as u32)));
+}
+
+criterion_group!(benches, bench_submits);
+criterion_main!(benches);
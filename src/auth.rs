@@ -0,0 +1,93 @@
+// Stratum worker authorization. Clients authorize worker names with mining.authorize, and may
+// only submit shares under names they've authorized.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead,BufReader};
+
+/// Checks the worker name and password a stratum client gave in mining.authorize
+pub trait WorkerVerifier: Send + Sync {
+	fn verify(&self, worker: &str, password: &str) -> bool;
+}
+
+/// Accepts any worker name with any password
+pub struct AllowAllWorkers;
+
+impl WorkerVerifier for AllowAllWorkers {
+	fn verify(&self, _worker: &str, _password: &str) -> bool {
+		true
+	}
+}
+
+/// Worker names and passwords read from a file with one "name:password" per line. Blank lines and
+/// lines starting with # are ignored. An entry for "name" also covers any "name.rig" worker, so
+/// that an account's rigs can share its password.
+pub struct CredentialsFile {
+	passwords: HashMap<String, String>,
+}
+
+impl CredentialsFile {
+	pub fn load(path: &str) -> io::Result<Self> {
+		Self::parse(BufReader::new(File::open(path)?))
+	}
+
+	fn parse<R: BufRead>(reader: R) -> io::Result<Self> {
+		let mut passwords = HashMap::new();
+		for (idx, line) in reader.lines().enumerate() {
+			let line = line?;
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') { continue; }
+			let mut split = line.splitn(2, ':');
+			let name = split.next().unwrap();
+			let password = match split.next() {
+				Some(password) if !name.is_empty() => password,
+				_ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Line {} is not in name:password form", idx + 1))),
+			};
+			if passwords.insert(name.to_string(), password.to_string()).is_some() {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Line {} duplicates an earlier name", idx + 1)));
+			}
+		}
+		Ok(Self { passwords: passwords })
+	}
+}
+
+impl WorkerVerifier for CredentialsFile {
+	fn verify(&self, worker: &str, password: &str) -> bool {
+		let account = match self.passwords.get(worker) {
+			Some(expected) => return expected == password,
+			None => match worker.find('.') {
+				Some(idx) => &worker[..idx],
+				None => return false,
+			},
+		};
+		match self.passwords.get(account) {
+			Some(expected) => expected == password,
+			None => false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use auth::*;
+
+	#[test]
+	fn test_credentials_file() {
+		let creds = CredentialsFile::parse("# comment\nalice:hunter2\n\nbob.rig1:pass:with:colons\nbob:\n".as_bytes()).unwrap();
+		assert!(creds.verify("alice", "hunter2"));
+		assert!(!creds.verify("alice", "hunter3"));
+		assert!(creds.verify("alice.rig7", "hunter2"));
+		assert!(!creds.verify("alice.rig7", ""));
+		assert!(creds.verify("bob.rig1", "pass:with:colons"));
+		// bob.rig1 has its own entry, so bob's password doesn't work for it
+		assert!(!creds.verify("bob.rig1", ""));
+		assert!(creds.verify("bob.rig2", ""));
+		assert!(!creds.verify("carol", ""));
+		assert!(!creds.verify("", ""));
+
+		assert!(CredentialsFile::parse("alice\n".as_bytes()).is_err());
+		assert!(CredentialsFile::parse(":password\n".as_bytes()).is_err());
+		assert!(CredentialsFile::parse("alice:a\nalice:b\n".as_bytes()).is_err());
+	}
+}
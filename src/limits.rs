@@ -0,0 +1,311 @@
+// Connection caps, per-client message rate limits and temporary bans for miner-facing listeners.
+// One ClientLimiter is shared by every worker, and each accepted client holds a ClientSlot which
+// gives its connection back when dropped.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc,Mutex};
+use std::time::{Duration,Instant};
+
+#[derive(Clone)]
+pub struct LimitsConfig {
+	/// Maximum number of clients connected at once, across all listeners
+	pub max_clients: Option<usize>,
+	/// Maximum number of clients connected at once from a single IP
+	pub max_clients_per_ip: Option<usize>,
+	/// Maximum sustained messages per second from one client, which may also send this many at
+	/// once in a burst
+	pub max_msgs_per_sec: u32,
+	/// Number of protocol violations (including exceeding the message rate) after which an IP is
+	/// banned. Violations are forgotten once an IP has gone ban_duration without one.
+	pub ban_after_violations: u32,
+	pub ban_duration: Duration,
+}
+
+impl Default for LimitsConfig {
+	fn default() -> Self {
+		Self {
+			max_clients: None,
+			max_clients_per_ip: None,
+			max_msgs_per_sec: 100,
+			ban_after_violations: 10,
+			ban_duration: Duration::from_secs(600),
+		}
+	}
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RejectReason {
+	TooManyClients,
+	TooManyClientsFromIp,
+	Banned,
+}
+
+pub struct ClientLimiter {
+	config: LimitsConfig,
+	clients: usize,
+	clients_per_ip: HashMap<IpAddr, usize>,
+	/// Violation count and the time of the last violation
+	violations: HashMap<IpAddr, (u32, Instant)>,
+	/// Ban expiry, or None for bans given on the command line which never expire
+	bans: HashMap<IpAddr, Option<Instant>>,
+}
+
+impl ClientLimiter {
+	pub fn new(config: LimitsConfig) -> Arc<Mutex<ClientLimiter>> {
+		Arc::new(Mutex::new(ClientLimiter {
+			config: config,
+			clients: 0,
+			clients_per_ip: HashMap::new(),
+			violations: HashMap::new(),
+			bans: HashMap::new(),
+		}))
+	}
+
+	/// Bans ip, until the given duration has passed or forever if None
+	pub fn ban(&mut self, ip: IpAddr, duration: Option<Duration>) {
+		self.bans.insert(ip, duration.map(|duration| Instant::now() + duration));
+	}
+
+	/// Lists current bans along with how long each has left, if it expires
+	pub fn list_bans(&mut self) -> Vec<(IpAddr, Option<Duration>)> {
+		let now = Instant::now();
+		self.expire_bans(now);
+		let mut bans: Vec<_> = self.bans.iter().map(|(ip, expiry)| {
+			(*ip, expiry.map(|expiry| expiry - now))
+		}).collect();
+		bans.sort_by_key(|&(ip, _)| ip);
+		bans
+	}
+
+	/// Drops expired bans, as well as violations old enough that violation_at would forget them
+	fn expire_bans(&mut self, now: Instant) {
+		self.bans.retain(|_, expiry| match expiry {
+			&mut Some(expiry) => expiry > now,
+			&mut None => true,
+		});
+		let ban_duration = self.config.ban_duration;
+		self.violations.retain(|_, &mut (_, last)| now.duration_since(last) < ban_duration);
+	}
+
+	fn is_banned(&mut self, ip: &IpAddr, now: Instant) -> bool {
+		self.expire_bans(now);
+		self.bans.contains_key(ip)
+	}
+
+	/// Checks a new client connection from ip (None for Unix sockets, which are only subject to
+	/// max_clients) against our limits, handing back a ClientSlot for it if it may connect.
+	pub fn try_accept(limiter: &Arc<Mutex<ClientLimiter>>, ip: Option<IpAddr>) -> Result<ClientSlot, RejectReason> {
+		let max_msgs_per_sec = {
+			let mut us = limiter.lock().unwrap();
+			us.accept_at(ip, Instant::now())?;
+			us.config.max_msgs_per_sec
+		};
+		Ok(ClientSlot {
+			limiter: limiter.clone(),
+			ip: ip,
+			rate: RateBucket::new(max_msgs_per_sec),
+		})
+	}
+
+	fn accept_at(&mut self, ip: Option<IpAddr>, now: Instant) -> Result<(), RejectReason> {
+		if let Some(ref ip) = ip {
+			if self.is_banned(ip, now) {
+				return Err(RejectReason::Banned);
+			}
+		}
+		if let Some(max) = self.config.max_clients {
+			if self.clients >= max {
+				return Err(RejectReason::TooManyClients);
+			}
+		}
+		if let Some(ip) = ip {
+			let count = self.clients_per_ip.entry(ip).or_insert(0);
+			if let Some(max) = self.config.max_clients_per_ip {
+				if *count >= max {
+					return Err(RejectReason::TooManyClientsFromIp);
+				}
+			}
+			*count += 1;
+		}
+		self.clients += 1;
+		Ok(())
+	}
+
+	fn release(&mut self, ip: Option<IpAddr>) {
+		self.clients -= 1;
+		if let Some(ip) = ip {
+			let remove = match self.clients_per_ip.get_mut(&ip) {
+				Some(count) => {
+					*count -= 1;
+					*count == 0
+				},
+				None => false,
+			};
+			if remove {
+				self.clients_per_ip.remove(&ip);
+			}
+		}
+	}
+
+	/// Records a protocol violation from ip, returning true if it caused ip to be banned
+	fn violation_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+		let ban_duration = self.config.ban_duration;
+		let count = {
+			let entry = self.violations.entry(ip).or_insert((0, now));
+			if now.duration_since(entry.1) >= ban_duration {
+				entry.0 = 0;
+			}
+			entry.0 += 1;
+			entry.1 = now;
+			entry.0
+		};
+		if count >= self.config.ban_after_violations {
+			self.violations.remove(&ip);
+			// Don't shorten a longer (or permanent) ban already in place
+			if !self.bans.contains_key(&ip) {
+				self.bans.insert(ip, Some(now + ban_duration));
+			}
+			true
+		} else { false }
+	}
+}
+
+/// Allows up to max_per_sec messages at once, refilling at max_per_sec per second
+struct RateBucket {
+	max_per_sec: u32,
+	tokens: f64,
+	last_refill: Option<Instant>,
+}
+
+impl RateBucket {
+	fn new(max_per_sec: u32) -> Self {
+		Self { max_per_sec: max_per_sec, tokens: max_per_sec as f64, last_refill: None }
+	}
+
+	fn take_at(&mut self, now: Instant) -> bool {
+		if let Some(last_refill) = self.last_refill {
+			let elapsed = now.duration_since(last_refill);
+			let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+			self.tokens = (self.tokens + elapsed_secs * self.max_per_sec as f64).min(self.max_per_sec as f64);
+		}
+		self.last_refill = Some(now);
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			true
+		} else { false }
+	}
+}
+
+/// A connected client's claim on the limits, released when dropped
+pub struct ClientSlot {
+	limiter: Arc<Mutex<ClientLimiter>>,
+	ip: Option<IpAddr>,
+	rate: RateBucket,
+}
+
+impl ClientSlot {
+	/// Should be called for each message received, returns false if the client has exceeded its
+	/// message rate and should be treated as having violated the protocol
+	pub fn message_received(&mut self) -> bool {
+		self.rate.take_at(Instant::now())
+	}
+
+	/// Records a protocol violation by this client, potentially banning its IP
+	pub fn protocol_violation(&self) {
+		if let Some(ip) = self.ip {
+			if self.limiter.lock().unwrap().violation_at(ip, Instant::now()) {
+				println!("Banned {} after repeated protocol violations", ip);
+			}
+		}
+	}
+}
+
+impl Drop for ClientSlot {
+	fn drop(&mut self) {
+		self.limiter.lock().unwrap().release(self.ip);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use limits::*;
+
+	use std::net::IpAddr;
+	use std::time::{Duration,Instant};
+
+	#[test]
+	fn test_connection_caps() {
+		let limiter = ClientLimiter::new(LimitsConfig {
+			max_clients: Some(3),
+			max_clients_per_ip: Some(2),
+			..Default::default()
+		});
+		let ip_a: IpAddr = "10.0.0.1".parse().unwrap();
+		let ip_b: IpAddr = "10.0.0.2".parse().unwrap();
+
+		let a1 = ClientLimiter::try_accept(&limiter, Some(ip_a)).unwrap();
+		let _a2 = ClientLimiter::try_accept(&limiter, Some(ip_a)).unwrap();
+		assert_eq!(ClientLimiter::try_accept(&limiter, Some(ip_a)).err(), Some(RejectReason::TooManyClientsFromIp));
+		let _b1 = ClientLimiter::try_accept(&limiter, Some(ip_b)).unwrap();
+		assert_eq!(ClientLimiter::try_accept(&limiter, Some(ip_b)).err(), Some(RejectReason::TooManyClients));
+		assert_eq!(ClientLimiter::try_accept(&limiter, None).err(), Some(RejectReason::TooManyClients));
+
+		drop(a1);
+		assert!(ClientLimiter::try_accept(&limiter, Some(ip_a)).is_ok());
+		// The slot returned above was dropped immediately, so this one fits as well
+		let _unix = ClientLimiter::try_accept(&limiter, None).unwrap();
+		assert_eq!(limiter.lock().unwrap().clients, 3);
+	}
+
+	#[test]
+	fn test_violations_ban() {
+		let limiter = ClientLimiter::new(LimitsConfig {
+			ban_after_violations: 3,
+			ban_duration: Duration::from_secs(60),
+			..Default::default()
+		});
+		let ip: IpAddr = "10.0.0.1".parse().unwrap();
+		let start = Instant::now();
+
+		let mut us = limiter.lock().unwrap();
+		assert!(!us.violation_at(ip, start));
+		assert!(!us.violation_at(ip, start + Duration::from_secs(1)));
+		// Violations are forgotten after ban_duration without one
+		assert!(!us.violation_at(ip, start + Duration::from_secs(70)));
+		assert!(!us.violation_at(ip, start + Duration::from_secs(71)));
+		let ip_b: IpAddr = "10.0.0.2".parse().unwrap();
+		assert!(!us.violation_at(ip_b, start + Duration::from_secs(71)));
+		assert!(us.violation_at(ip, start + Duration::from_secs(72)));
+		assert_eq!(us.violations.len(), 1);
+
+		assert_eq!(us.accept_at(Some(ip), start + Duration::from_secs(73)), Err(RejectReason::Banned));
+		assert!(us.accept_at(Some(ip_b), start + Duration::from_secs(73)).is_ok());
+		assert!(us.accept_at(Some(ip), start + Duration::from_secs(133)).is_ok());
+		// ip_b's violation is pruned once it would be forgotten anyway
+		assert!(us.violations.is_empty());
+
+		us.ban(ip, None);
+		assert_eq!(us.accept_at(Some(ip), start + Duration::from_secs(100_000)), Err(RejectReason::Banned));
+		assert_eq!(us.list_bans(), vec![(ip, None)]);
+	}
+
+	#[test]
+	fn test_rate_bucket() {
+		let mut bucket = RateBucket::new(10);
+		let start = Instant::now();
+		for _ in 0..10 {
+			assert!(bucket.take_at(start));
+		}
+		assert!(!bucket.take_at(start));
+		assert!(!bucket.take_at(start + Duration::from_millis(50)));
+		assert!(bucket.take_at(start + Duration::from_millis(150)));
+		assert!(!bucket.take_at(start + Duration::from_millis(150)));
+		// Refills never exceed one second's worth
+		let later = start + Duration::from_secs(10);
+		for _ in 0..10 {
+			assert!(bucket.take_at(later));
+		}
+		assert!(!bucket.take_at(later));
+	}
+}
@@ -3,9 +3,15 @@ extern crate bytes;
 extern crate futures;
 extern crate tokio;
 extern crate tokio_io;
+extern crate tokio_codec;
 extern crate tokio_timer;
 extern crate crypto;
 extern crate secp256k1;
+extern crate rand;
+extern crate native_tls;
+extern crate tokio_tls;
+extern crate tokio_uds;
+extern crate tokio_signal;
 
 #[macro_use]
 extern crate serde_json;
@@ -19,6 +25,23 @@ use stratum_server::*;
 mod mining_server;
 use mining_server::*;
 
+mod limits;
+use limits::{ClientLimiter,ClientSlot,LimitsConfig};
+
+mod vardiff;
+use vardiff::VardiffConfig;
+
+mod shares;
+use shares::{DuplicateShareCounts,JobRetention};
+
+mod auth;
+use auth::{AllowAllWorkers,CredentialsFile,WorkerVerifier};
+
+mod socks5;
+
+mod tls;
+use tls::{TlsPin,UpstreamTls};
+
 mod utils;
 
 use bitcoin::blockdata::transaction::{TxOut,Transaction};
@@ -30,29 +53,40 @@ use bitcoin::util::hash::Sha256dHash;
 use bytes::BufMut;
 
 use futures::future;
+use futures::sync::mpsc as sync_mpsc;
+use futures::sync::oneshot as sync_oneshot;
 use futures::unsync::{mpsc,oneshot};
-use futures::{Future,Stream,Sink};
+use futures::{Async,Future,Poll,Stream,Sink};
+use futures::stream;
 
 use tokio::executor::current_thread;
 use tokio::net;
 
-use tokio_io::{AsyncRead,codec};
+use tokio_io::{AsyncRead,AsyncWrite,codec};
 
 use tokio_timer::Timer;
 
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 
-use secp256k1::key::PublicKey;
+use secp256k1::key::{PublicKey,SecretKey};
 use secp256k1::Secp256k1;
 
-use std::cell::RefCell;
+use rand::Rng;
+
+use std::cell::{Cell,RefCell};
 use std::collections::HashMap;
-use std::{env,io,marker};
-use std::net::{SocketAddr,ToSocketAddrs};
+use std::{cmp,env,io,marker};
+use std::fs;
+use std::net::{IpAddr,SocketAddr,ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
 use std::str::FromStr;
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
 
 /// A future, essentially
 struct Eventual<Value> {
@@ -95,27 +129,97 @@ impl<Value: 'static> Eventual<Value> {
 	}
 }
 
+/// Tracks the Ping we have outstanding on an upstream connection and the resulting round-trip time
+struct Keepalive {
+	next_nonce: u64,
+	outstanding: Option<(u64, Instant)>,
+	rtt: Option<Duration>,
+}
+impl Keepalive {
+	fn new() -> Self {
+		Keepalive {
+			next_nonce: 0,
+			outstanding: None,
+			rtt: None,
+		}
+	}
+
+	fn reset(&mut self) {
+		self.outstanding = None;
+		self.rtt = None;
+	}
+
+	/// Gets the nonce for a new Ping, or None if our last one has yet to be answered
+	fn next_ping(&mut self) -> Option<u64> {
+		if self.outstanding.is_some() { return None; }
+		let nonce = self.next_nonce;
+		self.next_nonce += 1;
+		self.outstanding = Some((nonce, Instant::now()));
+		Some(nonce)
+	}
+
+	/// Returns false if the Pong doesn't match our outstanding Ping
+	fn pong_received(&mut self, nonce: u64) -> bool {
+		match self.outstanding {
+			Some((expected, sent)) if expected == nonce => {
+				self.rtt = Some(sent.elapsed());
+				self.outstanding = None;
+				true
+			},
+			_ => false,
+		}
+	}
+}
+
+/// A job provider connection's outgoing messages, which counts down the winning nonces among them
+/// as the connection's writer takes them to send
+pub struct WorkMessageQueue {
+	messages: mpsc::UnboundedReceiver<WorkMessage>,
+	pending_nonces: Rc<Cell<u64>>,
+}
+impl Stream for WorkMessageQueue {
+	type Item = WorkMessage;
+	type Error = ();
+	fn poll(&mut self) -> Poll<Option<WorkMessage>, ()> {
+		let res = self.messages.poll();
+		if let Ok(Async::Ready(Some(WorkMessage::WinningNonce { .. }))) = res {
+			self.pending_nonces.set(self.pending_nonces.get() - 1);
+		}
+		res
+	}
+}
+
 pub struct JobProviderHandler {
 	stream: Option<mpsc::UnboundedSender<WorkMessage>>,
+	/// Winning nonces queued on the current connection which haven't yet been taken to be written
+	pending_nonces: Rc<Cell<u64>>,
 	auth_key: Option<PublicKey>,
 
+	handshake_complete: bool,
+	keepalive_enabled: bool,
+	keepalive: Keepalive,
 	cur_template: Option<BlockTemplate>,
 	cur_prefix_postfix: Option<CoinbasePrefixPostfix>,
 
-	pending_tx_data_requests: HashMap<u64, oneshot::Sender<TransactionData>>,
-	job_stream: mpsc::Sender<(BlockTemplate, Option<CoinbasePrefixPostfix>, Rc<RefCell<Eventual<TransactionData>>>)>,
+	/// Each template's transactions are shared by every job built on it (and the workers' copies)
+	pending_tx_data_requests: HashMap<u64, oneshot::Sender<Arc<Vec<Transaction>>>>,
+	job_stream: mpsc::Sender<(BlockTemplate, Option<CoinbasePrefixPostfix>, Rc<RefCell<Eventual<Arc<Vec<Transaction>>>>>)>,
 
 	secp_ctx: Secp256k1,
 }
 
 impl JobProviderHandler {
-	fn new(expected_auth_key: Option<PublicKey>) -> (Rc<RefCell<JobProviderHandler>>, mpsc::Receiver<(BlockTemplate, Option<CoinbasePrefixPostfix>, Rc<RefCell<Eventual<TransactionData>>>)>) {
+	fn new(expected_auth_key: Option<PublicKey>) -> (Rc<RefCell<JobProviderHandler>>, mpsc::Receiver<(BlockTemplate, Option<CoinbasePrefixPostfix>, Rc<RefCell<Eventual<Arc<Vec<Transaction>>>>>)>) {
 		let (work_sender, work_receiver) = mpsc::channel(10);
 
 		(Rc::new(RefCell::new(JobProviderHandler {
 			stream: None,
+			pending_nonces: Rc::new(Cell::new(0)),
 			auth_key: expected_auth_key,
 
+			handshake_complete: false,
+			keepalive_enabled: false,
+			keepalive: Keepalive::new(),
 			cur_template: None,
 			cur_prefix_postfix: None,
 
@@ -132,7 +236,10 @@ impl JobProviderHandler {
 				match stream.unbounded_send(WorkMessage::WinningNonce {
 					nonces: work
 				}) {
-					Ok(_) => { println!("Submitted job-matching (ie full-block) nonce!"); },
+					Ok(_) => {
+						self.pending_nonces.set(self.pending_nonces.get() + 1);
+						println!("Submitted job-matching (ie full-block) nonce!");
+					},
 					Err(_) => { println!("Failed to submit job-matching (ie full-block) nonce as job provider disconnected"); }
 				}
 			},
@@ -141,33 +248,66 @@ impl JobProviderHandler {
 			}
 		}
 	}
+
+	fn pending_nonces(&self) -> u64 {
+		self.pending_nonces.get()
+	}
 }
 
 impl ConnectionHandler<WorkMessage> for Rc<RefCell<JobProviderHandler>> {
-	type Stream = mpsc::UnboundedReceiver<WorkMessage>;
+	type Stream = WorkMessageQueue;
 	type Framer = WorkMsgFramer;
 
-	fn new_connection(&mut self) -> (WorkMsgFramer, mpsc::UnboundedReceiver<WorkMessage>) {
+	fn new_connection(&mut self) -> (WorkMsgFramer, WorkMessageQueue) {
 		let mut us = self.borrow_mut();
 
 		let (mut tx, rx) = mpsc::unbounded();
 		match tx.start_send(WorkMessage::ProtocolSupport {
 			max_version: 1,
 			min_version: 1,
-			flags: 0,
+			flags: WORK_FLAG_KEEPALIVE,
 		}) {
 			Ok(_) => {
 				us.stream = Some(tx);
 			},
 			Err(_) => { panic!("Cant fail to send first message on an unbounded stream"); },
 		}
-		(WorkMsgFramer::new(), rx)
+		us.handshake_complete = false;
+		us.keepalive_enabled = false;
+		us.keepalive.reset();
+		// Anything queued on a previous connection went down with it
+		us.pending_nonces = Rc::new(Cell::new(0));
+		(WorkMsgFramer::new(), WorkMessageQueue {
+			messages: rx,
+			pending_nonces: us.pending_nonces.clone(),
+		})
 	}
 
 	fn connection_closed(&mut self) {
 		self.borrow_mut().stream = None;
 	}
 
+	fn is_ready(&self) -> bool {
+		self.borrow().handshake_complete
+	}
+
+	fn keepalive_enabled(&self) -> bool {
+		self.borrow().keepalive_enabled
+	}
+
+	fn send_ping(&mut self) {
+		let mut us = self.borrow_mut();
+		if let Some(nonce) = us.keepalive.next_ping() {
+			if let Some(ref stream) = us.stream {
+				let _ = stream.unbounded_send(WorkMessage::Ping { nonce });
+			}
+		}
+	}
+
+	fn get_rtt(&self) -> Option<Duration> {
+		self.borrow().keepalive.rtt
+	}
+
 	fn handle_message(&mut self, msg: WorkMessage) -> Result<(), io::Error> {
 		let mut us = self.borrow_mut();
 
@@ -205,7 +345,7 @@ impl ConnectionHandler<WorkMessage> for Rc<RefCell<JobProviderHandler>> {
 				if selected_version != 1 {
 					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 				}
-				if flags != 0 {
+				if (flags & !WORK_FLAG_KEEPALIVE) != 0 {
 					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 				}
 				if us.auth_key.is_none() {
@@ -216,6 +356,8 @@ impl ConnectionHandler<WorkMessage> for Rc<RefCell<JobProviderHandler>> {
 						return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 					}
 				}
+				us.handshake_complete = true;
+				us.keepalive_enabled = (flags & WORK_FLAG_KEEPALIVE) != 0;
 				println!("Received ProtocolVersion, using version {}", selected_version);
 			},
 			WorkMessage::BlockTemplate { signature, template } => {
@@ -274,7 +416,7 @@ impl ConnectionHandler<WorkMessage> for Rc<RefCell<JobProviderHandler>> {
 
 				match us.pending_tx_data_requests.remove(&data.template_id) {
 					Some(chan) => {
-						match chan.send(data) {
+						match chan.send(Arc::new(data.transactions)) {
 							Ok(()) => {},
 							Err(_) => {
 								println!("We gave up on job before job provider sent us transactions");
@@ -346,11 +488,84 @@ impl ConnectionHandler<WorkMessage> for Rc<RefCell<JobProviderHandler>> {
 				println!("Received WinningNonceHeader?");
 				return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 			},
+			WorkMessage::Ping { nonce } => {
+				if !us.keepalive_enabled {
+					println!("Received Ping without negotiating keepalives");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
+				match us.stream.as_ref().unwrap().unbounded_send(WorkMessage::Pong { nonce }) {
+					Ok(_) => {},
+					Err(_) => { panic!("unbounded streams should never fail"); }
+				}
+			},
+			WorkMessage::Pong { nonce } => {
+				if !us.keepalive.pong_received(nonce) {
+					println!("Received Pong for a Ping we did not send");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
+			},
+		}
+		Ok(())
+	}
+}
+
+/// Operator-configured limits on what a pool may do with the coinbase value
+struct PayoutPolicy {
+	/// Maximum fraction of the coinbase value which may be sent to the pool's appended outputs
+	max_appended_fraction: Option<f64>,
+	/// If set, every script the pool pays to must be in this list
+	allowed_scripts: Option<Vec<Script>>,
+	/// Minimum fraction of the coinbase value which must go to the pool's remaining_payout
+	min_remaining_fraction: Option<f64>,
+}
+
+impl PayoutPolicy {
+	fn check_scripts(&self, info: &PoolPayoutInfo) -> Result<(), String> {
+		match self.allowed_scripts {
+			Some(ref allowed) => {
+				if !allowed.contains(&info.remaining_payout) {
+					return Err(format!("remaining_payout to non-allowlisted script {}", utils::bytes_to_hex(&info.remaining_payout[..])));
+				}
+				for output in info.appended_outputs.iter() {
+					if !allowed.contains(&output.script_pubkey) {
+						return Err(format!("appended output to non-allowlisted script {}", utils::bytes_to_hex(&output.script_pubkey[..])));
+					}
+				}
+			},
+			None => {},
+		}
+		Ok(())
+	}
+
+	fn check_values(&self, info: &PoolPayoutInfo, remaining_value: u64, coinbase_value: u64) -> Result<(), String> {
+		if coinbase_value == 0 { return Ok(()); }
+		match self.max_appended_fraction {
+			Some(max_fraction) => {
+				let appended_value: u64 = info.appended_outputs.iter().map(|output| output.value).sum();
+				if appended_value as f64 > coinbase_value as f64 * max_fraction {
+					return Err(format!("{} of {} satoshis in appended outputs, more than the allowed fraction {}", appended_value, coinbase_value, max_fraction));
+				}
+			},
+			None => {},
+		}
+		match self.min_remaining_fraction {
+			Some(min_fraction) => {
+				if (remaining_value as f64) < coinbase_value as f64 * min_fraction {
+					return Err(format!("only {} of {} satoshis to remaining_payout, less than the required fraction {}", remaining_value, coinbase_value, min_fraction));
+				}
+			},
+			None => {},
 		}
 		Ok(())
 	}
 }
 
+/// After this many rejected shares in a row we stop using a pool for POOL_REJECT_FAILOVER_TIME
+const POOL_MAX_CONSECUTIVE_REJECTS: u32 = 10;
+const POOL_REJECT_FAILOVER_TIME: Duration = Duration::from_secs(5*60);
+/// The pool protocol features we know how to use
+const POOL_SUPPORTED_FLAGS: u16 = POOL_FLAG_SHARE_ACKS | POOL_FLAG_KEEPALIVE;
+
 struct PoolHandler {
 	pool_priority: usize,
 	stream: Option<mpsc::UnboundedSender<PoolMessage>>,
@@ -360,14 +575,30 @@ struct PoolHandler {
 	cur_payout_info: Option<PoolPayoutInfo>,
 	cur_difficulty: Option<PoolDifficulty>,
 	last_weak_block: Option<WeakBlock>,
-
+	handshake_complete: bool,
+	negotiated_flags: u16,
+	keepalive: Keepalive,
+
+	next_share_id: u64,
+	shares_submitted: u64,
+	shares_accepted: u64,
+	shares_rejected: u64,
+	shares_stale: u64,
+	/// Shares sent on the current connection which the pool has yet to send a ShareResult for
+	awaiting_acks: u64,
+	consecutive_rejects: u32,
+	failed_over_until: Option<Instant>,
+	/// Number of times this pool's payout info was refused by our payout policy
+	policy_violations: u64,
+
+	payout_policy: Rc<PayoutPolicy>,
 	job_stream: mpsc::Sender<(PoolPayoutInfo, Option<PoolDifficulty>)>,
 
 	secp_ctx: Secp256k1,
 }
 
 impl PoolHandler {
-	fn new(expected_auth_key: Option<PublicKey>, our_payout_addr: Address, pool_priority: usize) -> (Rc<RefCell<PoolHandler>>, mpsc::Receiver<(PoolPayoutInfo, Option<PoolDifficulty>)>) {
+	fn new(expected_auth_key: Option<PublicKey>, our_payout_addr: Address, pool_priority: usize, payout_policy: Rc<PayoutPolicy>) -> (Rc<RefCell<PoolHandler>>, mpsc::Receiver<(PoolPayoutInfo, Option<PoolDifficulty>)>) {
 		let (work_sender, work_receiver) = mpsc::channel(5);
 
 		(Rc::new(RefCell::new(PoolHandler {
@@ -379,7 +610,21 @@ impl PoolHandler {
 			cur_payout_info: None,
 			cur_difficulty: None,
 			last_weak_block: None,
-
+			handshake_complete: false,
+			negotiated_flags: 0,
+			keepalive: Keepalive::new(),
+
+			next_share_id: 0,
+			shares_submitted: 0,
+			shares_accepted: 0,
+			shares_rejected: 0,
+			shares_stale: 0,
+			awaiting_acks: 0,
+			consecutive_rejects: 0,
+			failed_over_until: None,
+			policy_violations: 0,
+
+			payout_policy,
 			job_stream: work_sender,
 
 			secp_ctx: Secp256k1::new(),
@@ -390,18 +635,44 @@ impl PoolHandler {
 		self.stream.is_some()
 	}
 
+	/// Connected and not currently failed-over due to rejecting our shares
+	fn is_usable(&self) -> bool {
+		if !self.is_connected() { return false; }
+		match self.failed_over_until {
+			Some(until) => Instant::now() >= until,
+			None => true,
+		}
+	}
+
+	fn get_payout_info(&self) -> Option<(PoolPayoutInfo, Option<PoolDifficulty>)> {
+		match self.cur_payout_info {
+			Some(ref info) => Some((info.clone(), self.cur_difficulty.clone())),
+			None => None,
+		}
+	}
+
+	fn pending_shares(&self) -> u64 {
+		if self.is_connected() { self.awaiting_acks } else { 0 }
+	}
+
 	fn get_priority(&self) -> usize {
 		self.pool_priority
 	}
 
-	fn send_nonce(&mut self, work: &(WinningNonce, Sha256dHash), template: &Rc<BlockTemplate>, post_coinbase_txn: &Vec<Transaction>) {
+	fn send_nonce(&mut self, work: &(WinningNonce, Sha256dHash), template: &Arc<BlockTemplate>, post_coinbase_txn: &Vec<Transaction>) {
 		match self.cur_difficulty {
 			Some(ref difficulty) => {
 				if utils::does_hash_meet_target(&work.1[..], &difficulty.share_target[..]) {
 					match self.stream {
 						Some(ref stream) => {
+							// Pools which don't ack shares don't expect an id either
+							let share_id = if (self.negotiated_flags & POOL_FLAG_SHARE_ACKS) != 0 {
+								self.next_share_id += 1;
+								Some(self.next_share_id - 1)
+							} else { None };
 							match stream.unbounded_send(PoolMessage::Share {
 								share: PoolShare {
+									share_id,
 									header_version: work.0.header_version,
 									header_prevblock: template.header_prevblock.clone(),
 									header_time: work.0.header_time,
@@ -412,7 +683,16 @@ impl PoolHandler {
 									user_tag: work.0.user_tag.clone(),
 								}
 							}) {
-								Ok(_) => { println!("Submitted share!"); },
+								Ok(_) => {
+									self.shares_submitted += 1;
+									match share_id {
+										Some(share_id) => {
+											self.awaiting_acks += 1;
+											println!("Submitted share {}!", share_id);
+										},
+										None => println!("Submitted share!"),
+									}
+								},
 								Err(_) => { println!("Failed to submit nonce as pool connection lost"); },
 							}
 						},
@@ -443,7 +723,7 @@ impl ConnectionHandler<PoolMessage> for Rc<RefCell<PoolHandler>> {
 		match tx.start_send(PoolMessage::ProtocolSupport {
 			max_version: 1,
 			min_version: 1,
-			flags: 0,
+			flags: POOL_SUPPORTED_FLAGS,
 		}) {
 			Ok(_) => {
 				us.stream = Some(tx);
@@ -452,6 +732,10 @@ impl ConnectionHandler<PoolMessage> for Rc<RefCell<PoolHandler>> {
 		}
 
 		us.last_weak_block = None;
+		us.handshake_complete = false;
+		us.negotiated_flags = 0;
+		us.awaiting_acks = 0;
+		us.keepalive.reset();
 		(PoolMsgFramer::new(), rx)
 	}
 
@@ -459,6 +743,31 @@ impl ConnectionHandler<PoolMessage> for Rc<RefCell<PoolHandler>> {
 		self.borrow_mut().stream = None;
 	}
 
+	fn is_ready(&self) -> bool {
+		self.borrow().handshake_complete
+	}
+
+	fn is_degraded(&self) -> bool {
+		!self.borrow().is_usable()
+	}
+
+	fn keepalive_enabled(&self) -> bool {
+		(self.borrow().negotiated_flags & POOL_FLAG_KEEPALIVE) != 0
+	}
+
+	fn send_ping(&mut self) {
+		let mut us = self.borrow_mut();
+		if let Some(nonce) = us.keepalive.next_ping() {
+			if let Some(ref stream) = us.stream {
+				let _ = stream.unbounded_send(PoolMessage::Ping { nonce });
+			}
+		}
+	}
+
+	fn get_rtt(&self) -> Option<Duration> {
+		self.borrow().keepalive.rtt
+	}
+
 	fn handle_message(&mut self, msg: PoolMessage) -> Result<(), io::Error> {
 		let mut us = self.borrow_mut();
 
@@ -495,7 +804,8 @@ impl ConnectionHandler<PoolMessage> for Rc<RefCell<PoolHandler>> {
 				if selected_version != 1 {
 					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 				}
-				if flags != 1 {
+				if (flags & !POOL_SUPPORTED_FLAGS) != 0 {
+					println!("Pool selected flags we did not offer ({})", flags);
 					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 				}
 				if us.auth_key.is_none() {
@@ -506,6 +816,8 @@ impl ConnectionHandler<PoolMessage> for Rc<RefCell<PoolHandler>> {
 						return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 					}
 				}
+				us.negotiated_flags = flags;
+				us.handshake_complete = true;
 
 				match us.stream.as_ref().unwrap().start_send(PoolMessage::PayoutInfoRequest {
 					user_id: us.our_payout_addr.to_string().into_bytes(),
@@ -516,7 +828,7 @@ impl ConnectionHandler<PoolMessage> for Rc<RefCell<PoolHandler>> {
 						return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 					}
 				}
-				println!("Received ProtocolVersion, using version {}", selected_version);
+				println!("Received ProtocolVersion, using version {} with flags {}", selected_version, flags);
 			},
 			PoolMessage::PayoutInfoRequest { .. } => {
 				println!("Received PayoutInfoRequest?");
@@ -537,6 +849,18 @@ impl ConnectionHandler<PoolMessage> for Rc<RefCell<PoolHandler>> {
 					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 				}
 
+				match us.payout_policy.check_scripts(&payout_info) {
+					Ok(()) => {},
+					Err(violation) => {
+						us.policy_violations += 1;
+						println!("ALERT: Pool {} sent payout info which violates our payout policy ({} violations so far): {}", us.pool_priority, us.policy_violations, violation);
+						println!("ALERT: Refusing to use pool {} for {} seconds", us.pool_priority, POOL_REJECT_FAILOVER_TIME.as_secs());
+						us.cur_payout_info = None;
+						us.failed_over_until = Some(Instant::now() + POOL_REJECT_FAILOVER_TIME);
+						return Ok(());
+					}
+				}
+
 				if us.cur_payout_info.is_none() || us.cur_payout_info.as_ref().unwrap().timestamp < payout_info.timestamp {
 					println!("Received new payout info!");
 					let cur_difficulty = us.cur_difficulty.clone();
@@ -576,18 +900,74 @@ impl ConnectionHandler<PoolMessage> for Rc<RefCell<PoolHandler>> {
 				return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 			},
 			PoolMessage::WeakBlockStateReset { } => {
+				if (us.negotiated_flags & POOL_FLAG_WEAK_BLOCKS) == 0 {
+					println!("Received WeakBlockStateReset without negotiating weak blocks");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
 				println!("Received WeakBlockStateReset");
 				us.last_weak_block = None;
 			},
 			PoolMessage::NewPoolServer { .. } => {
-				unimplemented!();
-			}
+				if (us.negotiated_flags & POOL_FLAG_REDIRECT) == 0 {
+					println!("Received NewPoolServer without negotiating redirect support");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
+				println!("Received NewPoolServer?");
+				return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+			},
+			PoolMessage::ShareResult { signature, result } => {
+				if (us.negotiated_flags & POOL_FLAG_SHARE_ACKS) == 0 {
+					println!("Received ShareResult without negotiating share acks");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
+				check_msg_sig!(9, result, signature);
+
+				us.awaiting_acks = us.awaiting_acks.saturating_sub(1);
+				match result.status {
+					ShareStatus::Accepted => {
+						us.shares_accepted += 1;
+						us.consecutive_rejects = 0;
+						println!("Pool accepted share {}", result.share_id);
+					},
+					ShareStatus::Stale => {
+						us.shares_stale += 1;
+						println!("Pool marked share {} stale", result.share_id);
+					},
+					ShareStatus::Rejected => {
+						us.shares_rejected += 1;
+						us.consecutive_rejects += 1;
+						println!("Pool rejected share {} ({:?})", result.share_id, result.reason);
+						if us.consecutive_rejects >= POOL_MAX_CONSECUTIVE_REJECTS {
+							println!("Pool rejected {} shares in a row, failing over to other pools for {} seconds", us.consecutive_rejects, POOL_REJECT_FAILOVER_TIME.as_secs());
+							us.failed_over_until = Some(Instant::now() + POOL_REJECT_FAILOVER_TIME);
+							us.consecutive_rejects = 0;
+						}
+					},
+				}
+				println!("Pool {} share totals: {} accepted, {} rejected, {} stale", us.pool_priority, us.shares_accepted, us.shares_rejected, us.shares_stale);
+			},
+			PoolMessage::Ping { nonce } => {
+				if (us.negotiated_flags & POOL_FLAG_KEEPALIVE) == 0 {
+					println!("Received Ping without negotiating keepalives");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
+				match us.stream.as_ref().unwrap().unbounded_send(PoolMessage::Pong { nonce }) {
+					Ok(_) => {},
+					Err(_) => { panic!("unbounded streams should never fail"); }
+				}
+			},
+			PoolMessage::Pong { nonce } => {
+				if !us.keepalive.pong_received(nonce) {
+					println!("Received Pong for a Ping we did not send");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
+			},
 		}
 		Ok(())
 	}
 }
 
-fn merge_job_pool(our_payout_script: Script, job_info: &Option<(BlockTemplate, Option<CoinbasePrefixPostfix>, Rc<RefCell<Eventual<TransactionData>>>)>, job_source: Option<Rc<RefCell<JobProviderHandler>>>, payout_info: &Option<(PoolPayoutInfo, Option<PoolDifficulty>)>, payout_source: Option<Rc<RefCell<PoolHandler>>>) -> Option<WorkInfo> {
+fn merge_job_pool(our_payout_script: Script, payout_policy: &PayoutPolicy, job_info: &Option<(BlockTemplate, Option<CoinbasePrefixPostfix>, Rc<RefCell<Eventual<Arc<Vec<Transaction>>>>>)>, job_source: Option<Rc<RefCell<JobProviderHandler>>>, payout_info: &Option<(PoolPayoutInfo, Option<PoolDifficulty>)>, payout_source: Option<Rc<RefCell<PoolHandler>>>) -> Option<WorkInfo> {
 	match job_info {
 		&Some((ref template_ref, ref coinbase_prefix_postfix, ref tx_data)) => {
 			let mut template = template_ref.clone();
@@ -629,6 +1009,21 @@ fn merge_job_pool(our_payout_script: Script, job_info: &Option<(BlockTemplate, O
 
 			match payout_info {
 				&Some((ref info, ref difficulty)) => {
+					match payout_policy.check_values(info, value_remaining as u64, template.coinbase_value_remaining) {
+						Ok(()) => {},
+						Err(violation) => {
+							match payout_source {
+								Some(ref source) => {
+									let mut source = source.borrow_mut();
+									source.policy_violations += 1;
+									println!("ALERT: Pool {} payout info violates our payout policy ({} violations so far): {}", source.pool_priority, source.policy_violations, violation);
+								},
+								None => println!("ALERT: Pool payout info violates our payout policy: {}", violation),
+							}
+							return None;
+						}
+					}
+
 					outputs.push(TxOut {
 						value: value_remaining as u64,
 						script_pubkey: info.remaining_payout.clone(),
@@ -659,12 +1054,13 @@ fn merge_job_pool(our_payout_script: Script, job_info: &Option<(BlockTemplate, O
 
 			template.appended_coinbase_outputs = outputs;
 
-			let template_rc = Rc::new(template);
+			println!("Built job {} with share target {:08x} (network target {:08x})", template.template_id, utils::target_to_nbits(&template.target), template.header_nbits);
+			let template_rc = Arc::new(template);
 
-			let (solution_tx, solution_rx) = mpsc::unbounded();
+			let (solution_tx, solution_rx) = sync_mpsc::unbounded();
 			let tx_data_ref = tx_data.clone();
 			let template_ref = template_rc.clone();
-			current_thread::spawn(solution_rx.for_each(move |nonces: Rc<(WinningNonce, Sha256dHash)>| {
+			current_thread::spawn(solution_rx.for_each(move |nonces: Arc<(WinningNonce, Sha256dHash)>| {
 				match job_source {
 					Some(ref source) => {
 						if utils::does_hash_meet_target(&nonces.1[..], &work_target[..]) {
@@ -679,7 +1075,7 @@ fn merge_job_pool(our_payout_script: Script, job_info: &Option<(BlockTemplate, O
 						let template_ref_2 = template_ref.clone();
 						tx_data_ref.borrow_mut().get_and(Box::new(move |txn| {
 							let source_clone = source_ref.clone();
-							source_clone.borrow_mut().send_nonce(&nonces, &template_ref_2, &txn.transactions);
+							source_clone.borrow_mut().send_nonce(&nonces, &template_ref_2, txn);
 						}));
 					},
 					None => {}
@@ -689,9 +1085,19 @@ fn merge_job_pool(our_payout_script: Script, job_info: &Option<(BlockTemplate, O
 				future::result(Ok(()))
 			}));
 
+			// Workers run on other threads, so hand them the transactions over a sync channel
+			let (txn_tx, txn_rx) = sync_oneshot::channel();
+			let txn_tx = RefCell::new(Some(txn_tx));
+			tx_data.borrow_mut().get_and(Box::new(move |txn| {
+				if let Some(txn_tx) = txn_tx.borrow_mut().take() {
+					let _ = txn_tx.send(txn.clone());
+				}
+			}));
+
 			Some(WorkInfo {
 				template: template_rc,
-				solutions: solution_tx
+				solutions: solution_tx,
+				transactions: txn_rx.shared(),
 			})
 		},
 		&None => None
@@ -704,45 +1110,358 @@ pub trait ConnectionHandler<MessageType> {
 	fn new_connection(&mut self) -> (Self::Framer, Self::Stream);
 	fn handle_message(&mut self, msg: MessageType) -> Result<(), io::Error>;
 	fn connection_closed(&mut self);
+	/// True once the protocol handshake has completed on the current connection
+	fn is_ready(&self) -> bool;
+	/// True if the connection is up but the handler does not currently want it used
+	fn is_degraded(&self) -> bool { false }
+	/// True if the peer agreed to answer our Pings on the current connection
+	fn keepalive_enabled(&self) -> bool { false }
+	/// Sends a Ping unless one is already outstanding
+	fn send_ping(&mut self) {}
+	/// Round-trip time of the last answered Ping
+	fn get_rtt(&self) -> Option<Duration> { None }
+}
+
+#[derive(Clone, Copy)]
+pub struct KeepaliveConfig {
+	/// How often to Ping peers which support it
+	pub interval: Duration,
+	/// How long a peer may go without sending us anything (or completing the handshake) before we
+	/// assume the connection is dead and reconnect
+	pub timeout: Duration,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConnectionState {
+	/// Resolving the host or waiting on a TCP connection
+	Connecting,
+	/// Connected, waiting on the protocol handshake
+	Handshaking,
+	Healthy,
+	/// Connected and handshaken, but the handler reports it should not be used
+	Degraded,
+	/// All addresses failed, waiting before we try again
+	BackingOff,
+}
+
+const CONNECT_BACKOFF_BASE: Duration = Duration::from_secs(1);
+const CONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5*60);
+/// How long a connection must stay past its handshake before we consider the upstream recovered
+/// and reset our backoff
+const CONNECT_STABLE_TIME: Duration = Duration::from_secs(60);
+
+pub struct ConnectionHealth {
+	state: ConnectionState,
+	state_since: Instant,
+	cur_addr: Option<SocketAddr>,
+	rtt: Option<Duration>,
+	/// Number of connection cycles in a row which did not stay up for CONNECT_STABLE_TIME after
+	/// completing the handshake
+	consecutive_failures: u32,
+	/// When the current connection completed its handshake, if it has
+	ready_since: Option<Instant>,
+	addr_failures: HashMap<SocketAddr, u32>,
+}
+
+impl ConnectionHealth {
+	fn new() -> Self {
+		ConnectionHealth {
+			state: ConnectionState::Connecting,
+			state_since: Instant::now(),
+			cur_addr: None,
+			rtt: None,
+			consecutive_failures: 0,
+			ready_since: None,
+			addr_failures: HashMap::new(),
+		}
+	}
+
+	fn set_state(&mut self, state: ConnectionState) {
+		if self.state != state {
+			self.state = state;
+			self.state_since = Instant::now();
+		}
+	}
+
+	pub fn get_state(&self) -> ConnectionState {
+		self.state
+	}
+
+	pub fn get_state_duration(&self) -> Duration {
+		self.state_since.elapsed()
+	}
+
+	pub fn get_rtt(&self) -> Option<Duration> {
+		self.rtt
+	}
+
+	pub fn get_consecutive_failures(&self) -> u32 {
+		self.consecutive_failures
+	}
+
+	fn addr_failed(&mut self, addr: SocketAddr) {
+		*self.addr_failures.entry(addr).or_insert(0) += 1;
+	}
+
+	/// Exponential backoff (from CONNECT_BACKOFF_BASE up to CONNECT_BACKOFF_MAX) with the delay
+	/// picked uniformly from its upper half so that proxies restarted together don't reconnect in
+	/// lockstep.
+	fn backoff_delay(&self) -> Duration {
+		let exp = std::cmp::min(self.consecutive_failures.saturating_sub(1), 16);
+		let max_ms = CONNECT_BACKOFF_MAX.as_secs() * 1000;
+		let delay_ms = std::cmp::min(CONNECT_BACKOFF_BASE.as_secs() * 1000 * (1 << exp), max_ms);
+		Duration::from_millis(rand::thread_rng().gen_range(delay_ms / 2, delay_ms + 1))
+	}
+}
+
+/// How to reach a job provider or pool
+#[derive(Clone)]
+pub struct Upstream {
+	host: String,
+	proxy: Option<SocketAddr>,
+	tls: Option<UpstreamTls>,
 }
 
 pub struct ConnectionMaintainer<MessageType: 'static, HandlerProvider : ConnectionHandler<MessageType>> {
 	host: String,
 	cur_addrs: Option<Vec<SocketAddr>>,
 	handler: HandlerProvider,
+	health: Rc<RefCell<ConnectionHealth>>,
+	keepalive: KeepaliveConfig,
+	/// Incremented on each new connection so that per-connection timers know when to stop
+	conn_id: u64,
+	last_recv: Instant,
+	/// Tears down the current connection when fired
+	kill_conn: Option<oneshot::Sender<()>>,
+	/// If set, we connect through this SOCKS5 proxy, which also resolves host for us
+	proxy: Option<SocketAddr>,
+	tls: Option<UpstreamTls>,
 	ph : marker::PhantomData<&'static MessageType>,
 }
 
 pub static mut TIMER: Option<Timer> = None;
+
+/// Resolves host on its own thread, as to_socket_addrs() blocks and a slow resolver would otherwise
+/// stall every connection we have on the event loop.
+fn resolve_host(host: String) -> Box<Future<Item=Vec<SocketAddr>, Error=()>> {
+	let (tx, rx) = sync_oneshot::channel();
+	thread::spawn(move || {
+		let _ = tx.send(match host.to_socket_addrs() {
+			Ok(addrs) => Ok(addrs.collect()),
+			Err(_) => Err(()),
+		});
+	});
+	Box::new(rx.then(|res| -> Result<Vec<SocketAddr>, ()> {
+		match res {
+			Ok(Ok(addrs)) => Ok(addrs),
+			_ => Err(()),
+		}
+	}))
+}
+
 impl<MessageType, HandlerProvider : 'static + ConnectionHandler<MessageType>> ConnectionMaintainer<MessageType, HandlerProvider> {
-	pub fn new(host: String, handler: HandlerProvider) -> ConnectionMaintainer<MessageType, HandlerProvider> {
+	pub fn new(upstream: Upstream, handler: HandlerProvider, keepalive: KeepaliveConfig) -> ConnectionMaintainer<MessageType, HandlerProvider> {
 		ConnectionMaintainer {
-			host: host,
+			host: upstream.host,
 			cur_addrs: None,
 			handler: handler,
+			health: Rc::new(RefCell::new(ConnectionHealth::new())),
+			keepalive,
+			conn_id: 0,
+			last_recv: Instant::now(),
+			kill_conn: None,
+			proxy: upstream.proxy,
+			tls: upstream.tls,
 			ph: marker::PhantomData,
 		}
 	}
 
+	pub fn get_health(&self) -> Rc<RefCell<ConnectionHealth>> {
+		self.health.clone()
+	}
+
+	fn update_health(&mut self) {
+		let mut health = self.health.borrow_mut();
+		health.rtt = self.handler.get_rtt();
+		if !self.handler.is_ready() {
+			health.set_state(ConnectionState::Handshaking);
+		} else {
+			let ready_since = *health.ready_since.get_or_insert_with(Instant::now);
+			if health.consecutive_failures != 0 && ready_since.elapsed() >= CONNECT_STABLE_TIME {
+				health.consecutive_failures = 0;
+				if let Some(addr) = health.cur_addr {
+					health.addr_failures.remove(&addr);
+				}
+			}
+			if self.handler.is_degraded() {
+				health.set_state(ConnectionState::Degraded);
+			} else {
+				health.set_state(ConnectionState::Healthy);
+			}
+		}
+	}
+
+	/// Called every keepalive interval while connected
+	fn check_keepalive(&mut self) {
+		let silent_for = self.last_recv.elapsed();
+		if (self.handler.keepalive_enabled() || !self.handler.is_ready()) && silent_for > self.keepalive.timeout {
+			println!("Heard nothing from {} in {} seconds, reconnecting...", self.host, silent_for.as_secs());
+			if let Some(kill) = self.kill_conn.take() {
+				let _ = kill.send(());
+			}
+			return;
+		}
+		if self.handler.keepalive_enabled() {
+			self.handler.send_ping();
+		}
+	}
+
+	fn retry_later(rc: Rc<RefCell<Self>>) {
+		let delay = {
+			let us = rc.borrow();
+			let mut health = us.health.borrow_mut();
+			health.consecutive_failures += 1;
+			health.set_state(ConnectionState::BackingOff);
+			let delay = health.backoff_delay();
+			println!("Retrying connection to {} in {} ms", us.host, delay.as_secs() * 1000 + delay.subsec_nanos() as u64 / 1_000_000);
+			delay
+		};
+		let timer: &Timer = unsafe { TIMER.as_ref().unwrap() };
+		current_thread::spawn(timer.sleep(delay).then(move |_| -> future::FutureResult<(), ()> {
+			Self::make_connection(rc);
+			future::result(Ok(()))
+		}));
+	}
+
+	/// Runs the protocol over a newly-established connection to addr until it is closed
+	fn run_connection<S: AsyncRead + AsyncWrite + 'static>(rc: Rc<RefCell<Self>>, stream: S, addr: Option<SocketAddr>) {
+		let (kill_tx, kill_rx) = oneshot::channel();
+		let (framer, tx_stream, conn_id) = {
+			let mut us = rc.borrow_mut();
+			{
+				let mut health = us.health.borrow_mut();
+				health.cur_addr = addr;
+				health.ready_since = None;
+			}
+			us.conn_id += 1;
+			us.last_recv = Instant::now();
+			us.kill_conn = Some(kill_tx);
+			let (framer, tx_stream) = us.handler.new_connection();
+			us.update_health();
+			(framer, tx_stream, us.conn_id)
+		};
+
+		let rc_keepalive = rc.clone();
+		let timer: &Timer = unsafe { TIMER.as_ref().unwrap() };
+		current_thread::spawn(timer.interval(rc.borrow().keepalive.interval).map_err(|_| ()).for_each(move |_| {
+			let mut us = rc_keepalive.borrow_mut();
+			if us.conn_id != conn_id || us.kill_conn.is_none() {
+				// Connection is gone, stop the timer
+				return future::result(Err(()));
+			}
+			us.update_health();
+			us.check_keepalive();
+			future::result(Ok(()))
+		}).then(|_| {
+			future::result(Ok(()))
+		}));
+
+		let (tx, rx) = stream.framed(framer).split();
+		let stream = tx_stream.map_err(|_| -> io::Error {
+			panic!("mpsc streams cant generate errors!");
+		});
+		current_thread::spawn(tx.send_all(stream).then(|_| {
+			println!("Disconnected on send side, will reconnect...");
+			future::result(Ok(()))
+		}));
+		let rc_clone = rc.clone();
+		let rc_clone_2 = rc.clone();
+		current_thread::spawn(rx.for_each(move |msg| {
+			let mut us = rc_clone.borrow_mut();
+			us.last_recv = Instant::now();
+			let res = us.handler.handle_message(msg);
+			us.update_health();
+			future::result(res)
+		}).select(kill_rx.then(|_| -> Result<(), io::Error> {
+			Err(io::Error::new(io::ErrorKind::TimedOut, utils::HandleError))
+		})).then(move |_| {
+			println!("Disconnected on recv side, will reconnect...");
+			{
+				let mut us = rc_clone_2.borrow_mut();
+				us.kill_conn = None;
+				let handshake_completed = us.handler.is_ready();
+				us.handler.connection_closed();
+				// Start a new cycle, re-resolving the host
+				us.cur_addrs = None;
+				if let (false, Some(addr)) = (handshake_completed, addr) {
+					us.health.borrow_mut().addr_failed(addr);
+				}
+			}
+			// Even after a completed handshake, don't spin reconnecting to a host which
+			// keeps dropping us - the backoff only resets once a connection stays up for
+			// CONNECT_STABLE_TIME
+			Self::retry_later(rc);
+			future::result(Ok(()))
+		}));
+	}
+
+	fn make_unix_connection(rc: Rc<RefCell<Self>>) {
+		let path = rc.borrow().host.split_at(5).1.to_string();
+		println!("Trying connection to {}", path);
+		current_thread::spawn(tokio_uds::UnixStream::connect(&path).then(move |res| -> future::FutureResult<(), ()> {
+			match res {
+				Ok(stream) => {
+					println!("Connected to {}!", path);
+					Self::run_connection(rc, stream, None);
+				},
+				Err(_) => Self::retry_later(rc),
+			}
+			future::result(Ok(()))
+		}));
+	}
+
 	pub fn make_connection(rc: Rc<RefCell<Self>>) {
-		if {
+		let host = {
 			let mut us = rc.borrow_mut();
+			us.health.borrow_mut().set_state(ConnectionState::Connecting);
+			if us.host.starts_with("unix:") {
+				drop(us);
+				Self::make_unix_connection(rc);
+				return;
+			}
 			if us.cur_addrs.is_none() {
-				//TODO: Resolve async
-				match us.host.to_socket_addrs() {
-					Err(_) => {
-						true
+				match us.proxy {
+					// The proxy resolves the host, so all we have to connect to is the proxy itself
+					Some(proxy) => { us.cur_addrs = Some(vec![proxy]); None },
+					None => Some(us.host.clone()),
+				}
+			} else { None }
+		};
+		if let Some(host) = host {
+			// Resolve fresh at the start of every connection cycle so that we pick up DNS changes
+			current_thread::spawn(resolve_host(host).then(move |res| -> future::FutureResult<(), ()> {
+				match res {
+					Ok(mut addrs) => {
+						if addrs.is_empty() {
+							Self::retry_later(rc);
+						} else {
+							{
+								// We pop() addresses off the end, so order them such that we try
+								// addresses which have failed least first, in resolver order.
+								let us = rc.borrow();
+								let health = us.health.borrow();
+								addrs.reverse();
+								addrs.sort_by_key(|addr| std::cmp::Reverse(*health.addr_failures.get(addr).unwrap_or(&0)));
+							}
+							rc.borrow_mut().cur_addrs = Some(addrs);
+							Self::make_connection(rc);
+						}
 					},
-					Ok(addrs) => {
-						us.cur_addrs = Some(addrs.collect());
-						false
+					Err(_) => {
+						println!("Failed to resolve {}", rc.borrow().host);
+						Self::retry_later(rc);
 					}
 				}
-			} else { false }
-		} {
-			let timer: &Timer = unsafe { TIMER.as_ref().unwrap() };
-			current_thread::spawn(timer.sleep(Duration::from_secs(10)).then(move |_| -> future::FutureResult<(), ()> {
-				Self::make_connection(rc);
 				future::result(Ok(()))
 			}));
 			return;
@@ -759,35 +1478,43 @@ impl<MessageType, HandlerProvider : 'static + ConnectionHandler<MessageType>> Co
 
 		match addr_option {
 			Some(addr) => {
-				println!("Trying connection to {}", addr);
+				let connect_future = match rc.borrow().proxy {
+					Some(_) => {
+						println!("Trying connection to {} via SOCKS5 proxy {}", rc.borrow().host, addr);
+						socks5::connect(&addr, &rc.borrow().host)
+					},
+					None => {
+						println!("Trying connection to {}", addr);
+						Box::new(net::TcpStream::connect(&addr))
+					}
+				};
 
-				current_thread::spawn(net::TcpStream::connect(&addr).then(move |res| -> future::FutureResult<(), ()> {
+				current_thread::spawn(connect_future.then(move |res| -> future::FutureResult<(), ()> {
 					match res {
 						Ok(stream) => {
-							println!("Connected to {}!", stream.peer_addr().unwrap());
+							println!("Connected to {}!", rc.borrow().host);
 							stream.set_nodelay(true).unwrap();
 
-							let (framer, tx_stream) = rc.borrow_mut().handler.new_connection();
-							let (tx, rx) = stream.framed(framer).split();
-							let stream = tx_stream.map_err(|_| -> io::Error {
-								panic!("mpsc streams cant generate errors!");
-							});
-							current_thread::spawn(tx.send_all(stream).then(|_| {
-								println!("Disconnected on send side, will reconnect...");
-								future::result(Ok(()))
-							}));
-							let rc_clone = rc.clone();
-							let rc_clone_2 = rc.clone();
-							current_thread::spawn(rx.for_each(move |msg| {
-								future::result(rc_clone.borrow_mut().handler.handle_message(msg))
-							}).then(move |_| {
-								println!("Disconnected on recv side, will reconnect...");
-								rc_clone_2.borrow_mut().handler.connection_closed();
-								Self::make_connection(rc);
-								future::result(Ok(()))
-							}));
+							let tls = rc.borrow().tls.clone();
+							match tls {
+								Some(tls) => {
+									let host = rc.borrow().host.clone();
+									current_thread::spawn(tls::connect(stream, &host, &tls).then(move |res| {
+										match res {
+											Ok(stream) => Self::run_connection(rc, stream, Some(addr)),
+											Err(_) => {
+												rc.borrow().health.borrow_mut().addr_failed(addr);
+												Self::make_connection(rc);
+											}
+										}
+										future::result(Ok(()))
+									}));
+								},
+								None => Self::run_connection(rc, stream, Some(addr)),
+							}
 						},
 						Err(_) => {
+							rc.borrow().health.borrow_mut().addr_failed(addr);
 							Self::make_connection(rc);
 						}
 					};
@@ -795,86 +1522,747 @@ impl<MessageType, HandlerProvider : 'static + ConnectionHandler<MessageType>> Co
 				}));
 			},
 			None => {
-				let timer: &Timer = unsafe { TIMER.as_ref().unwrap() };
-				current_thread::spawn(timer.sleep(Duration::from_secs(10)).then(move |_| {
-					Self::make_connection(rc);
-					future::result(Ok(()))
-				}));
+				Self::retry_later(rc);
 			},
 		}
 	}
 }
 
+enum BalanceQuota {
+	/// Switch pools after this much time on one pool
+	Time(Duration),
+	/// Switch pools after this many shares submitted to one pool
+	Shares(u64),
+}
+
+/// Splits hashrate across pools in proportion to their weights by moving the payout source
+/// between pools whenever the current pool has used up its time slice or share quota.
+struct PoolBalancer {
+	quota: BalanceQuota,
+	weights: Vec<u64>,
+	time_used: Vec<Duration>,
+	slice_start: Instant,
+	slice_start_shares: u64,
+	last_tick: Instant,
+	last_report: Instant,
+}
+
+impl PoolBalancer {
+	fn new(quota: BalanceQuota, weights: Vec<u64>) -> PoolBalancer {
+		let now = Instant::now();
+		PoolBalancer {
+			quota,
+			time_used: vec![Duration::from_secs(0); weights.len()],
+			weights,
+			slice_start: now,
+			slice_start_shares: 0,
+			last_tick: now,
+			last_report: now,
+		}
+	}
+}
+
+/// What to do once no pool has been usable for the configured grace period
+#[derive(Clone, Copy, PartialEq)]
+enum NoPoolPolicy {
+	/// Keep paying out to the last pool we used
+	KeepLastPayout,
+	/// Pay out to our own payout_address
+	Solo,
+	/// Stop handing out new work until a pool comes back
+	StopWork,
+}
+
+const POOL_SPLIT_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+const STATUS_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
 struct JobInfo {
 	payout_script: Script,
-	cur_job: Option<(BlockTemplate, Option<CoinbasePrefixPostfix>, Rc<RefCell<Eventual<TransactionData>>>)>,
+	payout_policy: Rc<PayoutPolicy>,
+	cur_job: Option<(BlockTemplate, Option<CoinbasePrefixPostfix>, Rc<RefCell<Eventual<Arc<Vec<Transaction>>>>>)>,
 	cur_job_source: Option<Rc<RefCell<JobProviderHandler>>>,
 	cur_pool: Option<(PoolPayoutInfo, Option<PoolDifficulty>)>,
 	cur_pool_source: Option<Rc<RefCell<PoolHandler>>>,
+	/// All pools, in priority order
+	pools: Vec<Rc<RefCell<PoolHandler>>>,
+	/// Connection health for each entry in pools
+	pool_links: Vec<Rc<RefCell<ConnectionHealth>>>,
+	job_provider_links: Vec<(String, Rc<RefCell<ConnectionHealth>>)>,
+	/// If set, we split hashrate across pools instead of using the highest-priority one
+	balancer: Option<PoolBalancer>,
+
+	no_pool_policy: NoPoolPolicy,
+	no_pool_grace: Duration,
+	all_pools_lost_since: Option<Instant>,
+	no_pool_fallback_active: bool,
+	no_pool_fallbacks: u64,
+	work_stopped: bool,
+	last_status_report: Instant,
+
 	job_tx: mpsc::Sender<WorkInfo>,
 }
 
-fn main() {
-	println!("USAGE: stratum-proxy (--job_provider=host:port)* (--pool_server=host:port)* --stratum_listen_bind=IP:port --mining_listen_bind=IP:port --mining_auth_key=base58privkey --payout_address=addr");
-	println!("--job_provider - bitcoind(s) running as mining server(s) to get work from");
-	println!("--pool_server - pool server(s) to get payout address from/submit shares to");
-	println!("--stratum_listen_bind - the address to bind to to announce stratum jobs on");
-	println!("--mining_listen_bind - the address to bind to to announce jobs on natively");
-	println!("--mining_auth_key - the auth key to use to authenticate to native clients");
-	println!("--payout_address - the Bitcoin address on which to receive payment");
-	println!("We always try to keep exactly one connection open per argument, no matter how");
-	println!("many hosts a DNS name may resolve to. We try each hostname until one works.");
-	println!("Job providers are not prioritized (the latest job is always used), pools are");
-	println!("prioritized in the order they appear on the command line.");
-
-	let mut job_provider_hosts = Vec::new();
-	let mut pool_server_hosts = Vec::new();
-	let mut stratum_listen_bind = None;
-	let mut mining_listen_bind = None;
-	let mut mining_auth_key = None;
-	let mut payout_addr = None;
+impl JobInfo {
+	fn announce_work(&mut self, work: WorkInfo) {
+		if self.work_stopped { return; }
+		match self.job_tx.start_send(work) {
+			Ok(_) => {},
+			Err(_) => {
+				println!("Job provider is providing work faster than we can process it");
+			}
+		}
+	}
 
-	for arg in env::args().skip(1) {
-		if arg.starts_with("--job_provider") {
-			match arg.split_at(15).1.to_socket_addrs() {
-				Err(_) => {
-					println!("Bad address resolution: {}", arg);
-					return;
-				},
-				Ok(_) => job_provider_hosts.push(arg.split_at(15).1.to_string())
+	/// Sets the pool whose payout info we're using. The balancer's slice restarts whenever we move
+	/// to a different pool, as a share quota counts only the current pool's shares.
+	fn set_cur_pool(&mut self, pool: Option<(PoolPayoutInfo, Option<PoolDifficulty>)>, source: Option<Rc<RefCell<PoolHandler>>>) {
+		let source_changed = match (&self.cur_pool_source, &source) {
+			(&Some(ref cur_source), &Some(ref new_source)) => !Rc::ptr_eq(cur_source, new_source),
+			(&None, &None) => false,
+			_ => true,
+		};
+		if source_changed {
+			if let Some(ref mut balancer) = self.balancer {
+				balancer.slice_start = Instant::now();
+				balancer.slice_start_shares = match source {
+					Some(ref source) => source.borrow().shares_submitted,
+					None => 0,
+				};
 			}
-		} else if arg.starts_with("--pool_server") {
-			match arg.split_at(14).1.to_socket_addrs() {
-				Err(_) => {
-					println!("Bad address resolution: {}", arg);
-					return;
+		}
+		self.cur_pool = pool;
+		self.cur_pool_source = source;
+	}
+
+	/// Switches to the given pool's payout info, building and announcing new work if we have a job
+	fn use_pool(&mut self, new_pool: (PoolPayoutInfo, Option<PoolDifficulty>), source: Rc<RefCell<PoolHandler>>) {
+		let new_pool = Some(new_pool);
+		match merge_job_pool(self.payout_script.clone(), &self.payout_policy, &self.cur_job, self.cur_job_source.clone(), &new_pool, Some(source.clone())) {
+			Some(work) => {
+				self.announce_work(work);
+				self.set_cur_pool(new_pool, Some(source));
+			},
+			None => {
+				if self.cur_job.is_none() {
+					self.set_cur_pool(new_pool, Some(source));
+				}
+			}
+		}
+	}
+
+	/// Switches to the highest-priority usable pool if we aren't already using it, eg because our
+	/// current pool disconnected or started rejecting our shares, or a better pool came back.
+	fn check_pool_failover(&mut self) {
+		let mut best_pool = None;
+		for pool in self.pools.iter() {
+			let pool_ref = pool.borrow();
+			if pool_ref.is_usable() {
+				if let Some(info) = pool_ref.get_payout_info() {
+					best_pool = Some((info, pool.clone()));
+					break;
+				}
+			}
+		}
+		match best_pool {
+			Some((info, source)) => {
+				let switch = match self.cur_pool_source {
+					Some(ref cur_pool) => !Rc::ptr_eq(cur_pool, &source),
+					None => true,
+				};
+				if switch {
+					println!("Failing over to pool {}", source.borrow().get_priority());
+					self.use_pool(info, source);
+				}
+			},
+			None => {},
+		}
+	}
+
+	/// Moves the payout source to the pool which is furthest behind its weighted share of
+	/// hashrate once the current pool has used up its slice. Note that shares for work which was
+	/// already handed out still go to the pool whose payout info is in that work's coinbase.
+	fn check_pool_balance(&mut self) {
+		let now = Instant::now();
+		let cur_idx = match self.cur_pool_source {
+			Some(ref pool) => Some(pool.borrow().get_priority()),
+			None => None,
+		};
+		let cur_usable = match self.cur_pool_source {
+			Some(ref pool) => pool.borrow().is_usable(),
+			None => false,
+		};
+
+		let best_pool = {
+			let balancer = self.balancer.as_mut().unwrap();
+			if let Some(idx) = cur_idx {
+				let elapsed = now.duration_since(balancer.last_tick);
+				balancer.time_used[idx] += elapsed;
+			}
+			balancer.last_tick = now;
+
+			let slice_done = match cur_idx {
+				Some(idx) => match balancer.quota {
+					BalanceQuota::Time(slice) => now.duration_since(balancer.slice_start) >= slice,
+					BalanceQuota::Shares(quota) => self.pools[idx].borrow().shares_submitted.saturating_sub(balancer.slice_start_shares) >= quota,
 				},
-				Ok(_) => pool_server_hosts.push(arg.split_at(14).1.to_string())
+				None => false,
+			};
+
+			let mut best_pool: Option<(f64, (PoolPayoutInfo, Option<PoolDifficulty>), Rc<RefCell<PoolHandler>>)> = None;
+			if slice_done || !cur_usable {
+				for (idx, pool) in self.pools.iter().enumerate() {
+					let pool_ref = pool.borrow();
+					if !pool_ref.is_usable() { continue; }
+					let info = match pool_ref.get_payout_info() {
+						Some(info) => info,
+						None => continue,
+					};
+					let used = match balancer.quota {
+						BalanceQuota::Time(_) => {
+							let time = balancer.time_used[idx];
+							time.as_secs() as f64 + time.subsec_nanos() as f64 / 1_000_000_000.0
+						},
+						BalanceQuota::Shares(_) => pool_ref.shares_submitted as f64,
+					};
+					let deficit = used / balancer.weights[idx] as f64;
+					if best_pool.is_none() || best_pool.as_ref().unwrap().0 > deficit {
+						best_pool = Some((deficit, info, pool.clone()));
+					}
+				}
 			}
-		} else if arg.starts_with("--stratum_listen_bind") {
-			if stratum_listen_bind.is_some() {
-				println!("Cannot specify multiple listen binds");
+			best_pool
+		};
+
+		match best_pool {
+			Some((_, info, source)) => {
+				let switch = match self.cur_pool_source {
+					Some(ref cur_pool) => !Rc::ptr_eq(cur_pool, &source),
+					None => true,
+				};
+				if switch {
+					println!("Moving hashrate to pool {}", source.borrow().get_priority());
+					self.use_pool(info, source);
+				} else {
+					// Staying on the same pool, so just start its next slice
+					let balancer = self.balancer.as_mut().unwrap();
+					balancer.slice_start = now;
+					balancer.slice_start_shares = source.borrow().shares_submitted;
+				}
+			},
+			None => {},
+		}
+
+		if now.duration_since(self.balancer.as_ref().unwrap().last_report) >= POOL_SPLIT_REPORT_INTERVAL {
+			self.report_pool_split();
+			self.balancer.as_mut().unwrap().last_report = now;
+		}
+	}
+
+	/// Applies our NoPoolPolicy once no pool has been usable for no_pool_grace, and undoes it once
+	/// one comes back.
+	fn check_all_pools_lost(&mut self) {
+		if self.pools.is_empty() { return; }
+		let any_usable = self.pools.iter().any(|pool| pool.borrow().is_usable());
+		if any_usable {
+			self.all_pools_lost_since = None;
+			if self.no_pool_fallback_active {
+				println!("A pool is usable again, leaving no-pool fallback");
+				self.no_pool_fallback_active = false;
+				let was_stopped = self.work_stopped;
+				self.work_stopped = false;
+				self.set_cur_pool(None, None);
+				if was_stopped {
+					// Make sure miners get work again even if no pool has sent payout info yet
+					match merge_job_pool(self.payout_script.clone(), &self.payout_policy, &self.cur_job, self.cur_job_source.clone(), &None, None) {
+						Some(work) => self.announce_work(work),
+						None => {},
+					}
+				}
+			}
+			return;
+		}
+
+		let now = Instant::now();
+		let lost_since = match self.all_pools_lost_since {
+			Some(since) => since,
+			None => {
+				self.all_pools_lost_since = Some(now);
+				now
+			}
+		};
+		if self.no_pool_fallback_active || now.duration_since(lost_since) < self.no_pool_grace {
+			return;
+		}
+
+		self.no_pool_fallback_active = true;
+		self.no_pool_fallbacks += 1;
+		match self.no_pool_policy {
+			NoPoolPolicy::KeepLastPayout => {
+				println!("No pool usable for {} seconds, continuing to pay out to the last pool", self.no_pool_grace.as_secs());
+			},
+			NoPoolPolicy::Solo => {
+				println!("No pool usable for {} seconds, switching to solo payout to our payout_address", self.no_pool_grace.as_secs());
+				if self.cur_pool.is_some() {
+					self.set_cur_pool(None, None);
+					match merge_job_pool(self.payout_script.clone(), &self.payout_policy, &self.cur_job, self.cur_job_source.clone(), &None, None) {
+						Some(work) => self.announce_work(work),
+						None => {},
+					}
+				}
+			},
+			NoPoolPolicy::StopWork => {
+				println!("No pool usable for {} seconds, no longer handing out new work", self.no_pool_grace.as_secs());
+				self.work_stopped = true;
+			},
+		}
+	}
+
+	fn report_status(&self) {
+		match self.cur_pool_source {
+			Some(ref pool) => println!("Status: paying out via pool {}, {} no-pool fallbacks", pool.borrow().get_priority(), self.no_pool_fallbacks),
+			None if self.work_stopped => println!("Status: not handing out work as no pool is usable, {} no-pool fallbacks", self.no_pool_fallbacks),
+			None => println!("Status: paying out to our own payout_address, {} no-pool fallbacks", self.no_pool_fallbacks),
+		}
+		for &(ref host, ref link) in self.job_provider_links.iter() {
+			let link = link.borrow();
+			println!("Job provider {}: {:?} for {} secs, {} failed connection attempts, rtt {}", host,
+				link.get_state(), link.get_state_duration().as_secs(), link.get_consecutive_failures(), format_rtt(link.get_rtt()));
+		}
+		for (pool, link) in self.pools.iter().zip(self.pool_links.iter()) {
+			let pool = pool.borrow();
+			let link = link.borrow();
+			println!("Pool {}: {}, {:?} for {} secs, {} failed connection attempts, rtt {}, {} shares submitted, {} accepted, {} rejected, {} stale, {} payout policy violations", pool.get_priority(),
+				if pool.is_usable() { "usable" } else if pool.is_connected() { "failed over" } else { "disconnected" },
+				link.get_state(), link.get_state_duration().as_secs(), link.get_consecutive_failures(), format_rtt(link.get_rtt()),
+				pool.shares_submitted, pool.shares_accepted, pool.shares_rejected, pool.shares_stale, pool.policy_violations);
+		}
+	}
+
+	fn report_pool_split(&self) {
+		let balancer = self.balancer.as_ref().unwrap();
+		let mut total_time = 0.0;
+		let mut total_shares = 0;
+		for (idx, pool) in self.pools.iter().enumerate() {
+			let time = balancer.time_used[idx];
+			total_time += time.as_secs() as f64 + time.subsec_nanos() as f64 / 1_000_000_000.0;
+			total_shares += pool.borrow().shares_submitted;
+		}
+		let total_weight: u64 = balancer.weights.iter().sum();
+		for (idx, pool) in self.pools.iter().enumerate() {
+			let time = balancer.time_used[idx];
+			let time_secs = time.as_secs() as f64 + time.subsec_nanos() as f64 / 1_000_000_000.0;
+			let shares = pool.borrow().shares_submitted;
+			println!("Pool {} split: weight {} (target {:.1}%), {:.1}% of time, {:.1}% of shares ({} shares)", idx, balancer.weights[idx],
+				balancer.weights[idx] as f64 * 100.0 / total_weight as f64,
+				if total_time > 0.0 { time_secs * 100.0 / total_time } else { 0.0 },
+				if total_shares > 0 { shares as f64 * 100.0 / total_shares as f64 } else { 0.0 },
+				shares);
+		}
+	}
+}
+
+/// Parses an upstream of the form unix:/path or host:port[,socks5=IP:port][,tls][,tls_pin=cert|spki:hex]
+fn parse_upstream(spec: &str) -> Option<Upstream> {
+	let mut parts = spec.split(',');
+	let host = parts.next().unwrap().to_string();
+	if host.starts_with("unix:") {
+		// Local Unix sockets can't be proxied and don't need TLS
+		if host.len() == 5 || parts.next().is_some() {
+			return None;
+		}
+		return Some(Upstream {
+			host,
+			proxy: None,
+			tls: None,
+		});
+	}
+	if socks5::split_host_port(&host).is_none() {
+		return None;
+	}
+	let mut upstream = Upstream {
+		host,
+		proxy: None,
+		tls: None,
+	};
+	for part in parts {
+		if part.starts_with("socks5=") && upstream.proxy.is_none() {
+			match part.split_at(7).1.parse() {
+				Ok(addr) => upstream.proxy = Some(addr),
+				Err(_) => return None,
+			}
+		} else if part == "tls" && upstream.tls.is_none() {
+			upstream.tls = Some(UpstreamTls { pin: None });
+		} else if part.starts_with("tls_pin=") && (upstream.tls.is_none() || upstream.tls.as_ref().unwrap().pin.is_none()) {
+			match TlsPin::parse(part.split_at(8).1) {
+				Some(pin) => upstream.tls = Some(UpstreamTls { pin: Some(pin) }),
+				None => return None,
+			}
+		} else {
+			return None;
+		}
+	}
+	Some(upstream)
+}
+
+enum ListenBind {
+	Tcp(SocketAddr),
+	Unix(PathBuf),
+}
+
+fn parse_listen_bind(bind: &str) -> Option<ListenBind> {
+	if bind.starts_with("unix:") {
+		if bind.len() == 5 { return None; }
+		Some(ListenBind::Unix(PathBuf::from(bind.split_at(5).1)))
+	} else {
+		match bind.parse() {
+			Ok(addr) => Some(ListenBind::Tcp(addr)),
+			Err(_) => None,
+		}
+	}
+}
+
+/// Binds a Unix socket listener, first removing any stale socket left behind by a previous run.
+/// A socket something is still listening on is left alone, so that we don't steal it from another
+/// running instance.
+fn bind_unix(path: &PathBuf) -> io::Result<tokio_uds::UnixListener> {
+	if let Ok(metadata) = fs::symlink_metadata(path) {
+		if metadata.file_type().is_socket() {
+			match std::os::unix::net::UnixStream::connect(path) {
+				Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, "socket is in use by another process")),
+				Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+				Err(e) => return Err(e),
+			}
+		}
+	}
+	tokio_uds::UnixListener::bind(path)
+}
+
+/// A client connection accepted by the main thread, not yet registered with any event loop
+enum AcceptedStream {
+	Tcp(std::net::TcpStream),
+	Unix(std::os::unix::net::UnixStream),
+}
+
+#[derive(Clone, Copy)]
+enum ServerType {
+	Stratum,
+	Mining,
+}
+
+enum WorkerCommand {
+	/// Serve a newly-accepted client, first completing a TLS handshake if an acceptor is given
+	NewConnection(AcceptedStream, ClientSlot, ServerType, Option<tokio_tls::TlsAcceptor>),
+	/// Ask all stratum clients to reconnect to host:port (as we're shutting down)
+	SendReconnect(String, u16),
+}
+
+/// Runs one worker thread. Each worker has its own StratumServer and MiningServer and serves the
+/// clients the main thread hands it, so the per-client work (JSON, merkle roots, signing header
+/// variants) is spread across cores while upstreams, jobs and solutions stay on the main thread.
+/// Workers only pass up shares which meet the job's (ie the pool's) share target, so the main
+/// thread sees far fewer shares than the workers, but it does handle all of those on one core.
+fn run_worker(worker_id: u16, stratum_jobs: Option<(sync_mpsc::Receiver<WorkInfo>, StratumConfig)>, mining_jobs: Option<(sync_mpsc::Receiver<WorkInfo>, SecretKey, JobRetention)>,
+		commands: sync_mpsc::UnboundedReceiver<WorkerCommand>, stratum_clients: Arc<AtomicUsize>, mining_clients: Arc<AtomicUsize>, duplicate_shares: Arc<DuplicateShareCounts>) {
+	current_thread::block_on_all(future::lazy(move || -> future::FutureResult<(), ()> {
+		let stratum_server = match stratum_jobs {
+			Some((job_rx, config)) => {
+				let timer: &Timer = unsafe { TIMER.as_ref().unwrap() };
+				let server = StratumServer::new(job_rx, worker_id, stratum_clients, config.clone(), duplicate_shares.clone(), timer.clone());
+				let server_ref = server.clone();
+				current_thread::spawn(timer.interval(Duration::from_secs(1)).for_each(move |_| {
+					let mut server = server_ref.borrow_mut();
+					server.retarget_clients(Instant::now());
+					server.renotify_idle_clients(config.renotify_interval, Instant::now());
+					server.close_unreconnected_clients(Instant::now());
+					future::result(Ok(()))
+				}).then(|_| {
+					future::result(Ok(()))
+				}));
+				Some(server)
+			},
+			None => None,
+		};
+		let mining_server = match mining_jobs {
+			Some((job_rx, auth_key, job_retention)) => Some(MiningServer::new(job_rx, auth_key, worker_id, mining_clients, job_retention, duplicate_shares)),
+			None => None,
+		};
+
+		current_thread::spawn(commands.for_each(move |command| {
+			match command {
+				WorkerCommand::NewConnection(stream, slot, server_type, acceptor) => {
+					macro_rules! serve_on {
+						($server: expr, $server_type: tt, $stream: expr) => {
+							match ($server, acceptor) {
+								(&Some(ref server), Some(acceptor)) => {
+									let server = server.clone();
+									current_thread::spawn(acceptor.accept($stream).then(move |res| {
+										match res {
+											Ok(stream) => $server_type::new_connection(server, stream, slot),
+											Err(e) => println!("TLS handshake with client failed: {}", e),
+										}
+										future::result(Ok(()))
+									}));
+								},
+								(&Some(ref server), None) => $server_type::new_connection(server.clone(), $stream, slot),
+								(&None, _) => panic!("Got a client for a server we aren't running"),
+							}
+						}
+					}
+					macro_rules! serve {
+						($stream: expr) => {
+							match server_type {
+								ServerType::Stratum => serve_on!(&stratum_server, StratumServer, $stream),
+								ServerType::Mining => serve_on!(&mining_server, MiningServer, $stream),
+							}
+						}
+					}
+					match stream {
+						AcceptedStream::Tcp(sock) => match net::TcpStream::from_std(sock, &tokio::reactor::Handle::default()) {
+							Ok(sock) => {
+								sock.set_nodelay(true).unwrap();
+								serve!(sock);
+							},
+							Err(e) => println!("Worker {} failed to register client connection: {}", worker_id, e),
+						},
+						AcceptedStream::Unix(sock) => match tokio_uds::UnixStream::from_std(sock, &tokio::reactor::Handle::default()) {
+							Ok(sock) => serve!(sock),
+							Err(e) => println!("Worker {} failed to register client connection: {}", worker_id, e),
+						},
+					}
+				},
+				WorkerCommand::SendReconnect(host, port) => {
+					if let Some(ref server) = stratum_server {
+						server.borrow_mut().send_reconnect(&host, port);
+					}
+				},
+			}
+			future::result(Ok(()))
+		}));
+
+		future::result(Ok(()))
+	})).unwrap();
+}
+
+fn format_rtt(rtt: Option<Duration>) -> String {
+	match rtt {
+		Some(rtt) => format!("{} ms", rtt.as_secs() * 1000 + rtt.subsec_nanos() as u64 / 1_000_000),
+		None => "unknown".to_string(),
+	}
+}
+
+fn main() {
+	println!("USAGE: stratum-proxy (--job_provider=host:port)* (--pool_server=host:port)* (--stratum_listen_bind=IP:port)* (--mining_listen_bind=IP:port)* --mining_auth_key=base58privkey --payout_address=addr");
+	println!("--job_provider - bitcoind(s) running as mining server(s) to get work from, either");
+	println!("                 host:port or unix:/path/to/socket");
+	println!("--pool_server - pool server(s) to get payout address from/submit shares to");
+	println!("                job_provider and pool_server may have \",socks5=IP:port\" appended to");
+	println!("                connect via a SOCKS5 proxy (eg Tor), which then does DNS resolution");
+	println!("                ,tls may also be appended to connect using TLS, or ,tls_pin=cert:hex or");
+	println!("                ,tls_pin=spki:hex to use TLS and require the certificate (or its public");
+	println!("                key) to have the given sha256 instead of validating it against CAs");
+	println!("--socks5_proxy - IP:port of a SOCKS5 proxy to use for upstreams which don't set one");
+	println!("--stratum_listen_bind - the address(es) to bind to to announce stratum jobs on");
+	println!("--mining_listen_bind - the address(es) to bind to to announce jobs on natively");
+	println!("--stratum_listen_bind_tls, --mining_listen_bind_tls - addresses to bind to to accept");
+	println!("                                                     TLS connections for each server");
+	println!("                       All listen binds may be given multiple times and may be either");
+	println!("                       IP:port or unix:/path/to/socket");
+	println!("--tls_cert - PEM certificate chain for the TLS listen binds");
+	println!("--tls_key - PEM (PKCS#8) private key for the TLS listen binds");
+	println!("--stratum_credentials_file - file of name:password lines which stratum workers must");
+	println!("                             authorize with, where name also covers name.anything");
+	println!("                             (by default any worker name and password is accepted)");
+	println!("                             A d=NNN in a password, eg \"secret,d=1024\", is taken as");
+	println!("                             a starting difficulty hint rather than checked");
+	println!("--stratum_get_transactions - txids or hex: whether mining.get_transactions answers with");
+	println!("                             each transaction's txid (the default) or its full hex");
+	println!("--mining_auth_key - the auth key to use to authenticate to native clients");
+	println!("--payout_address - the Bitcoin address on which to receive payment");
+	println!("--pool_weights - optional comma-separated weights, one per pool_server, to split");
+	println!("                 hashrate across pools instead of using them in priority order");
+	println!("--pool_balance - time:seconds or shares:count, how long to stay on one pool when");
+	println!("                 splitting hashrate (defaults to time:60)");
+	println!("--pool_max_appended_fraction - maximum fraction of the coinbase value a pool may");
+	println!("                               send to its own appended outputs");
+	println!("--pool_min_remaining_fraction - minimum fraction of the coinbase value which must");
+	println!("                                go to the pool's remaining_payout");
+	println!("--pool_allowed_script - an address or hex script a pool may pay to, may be given");
+	println!("                        multiple times. If any are given, all others are refused");
+	println!("--no_pool_policy - keep, solo or stop: what to do when no pool is usable, either keep");
+	println!("                   paying the last pool, pay payout_address, or stop handing out new");
+	println!("                   work (defaults to solo)");
+	println!("--no_pool_grace_secs - how long no pool must be usable before no_pool_policy applies");
+	println!("                       (defaults to 60)");
+	println!("--keepalive_interval_secs - how often to ping job providers and pools (defaults to 30)");
+	println!("--shutdown_reconnect - host:port to send stratum clients to via client.reconnect when");
+	println!("                       we get SIGTERM/SIGINT");
+	println!("--drain_timeout_secs - how long to wait on shutdown for winning nonces to be sent, pools");
+	println!("                       to acknowledge shares (and stratum clients to leave) before");
+	println!("                       exiting (defaults to 30)");
+	println!("--keepalive_timeout_secs - how long an upstream may go silent before we reconnect");
+	println!("                           (defaults to 90)");
+	println!("--worker_threads - number of threads to spread stratum and native clients across");
+	println!("                   (defaults to the number of CPUs)");
+	println!("--stratum_max_jobs - number of recent jobs stratum and native clients may submit");
+	println!("                     shares for (defaults to 16)");
+	println!("--stratum_max_job_age_secs - how long after we received a job stratum and native");
+	println!("                             clients may submit shares for it, once it is no longer");
+	println!("                             the latest job (defaults to 300)");
+	println!("--stratum_renotify_secs - how long a stratum client may go without hearing from us");
+	println!("                          before we re-send it the latest job (defaults to 30)");
+	println!("--stratum_min_difficulty - lowest difficulty given to stratum clients (defaults to 1)");
+	println!("--stratum_max_difficulty - highest (and starting) difficulty given to stratum");
+	println!("                           clients, which are never given more than the pool's share");
+	println!("                           difficulty, so by default they start at it");
+	println!("--stratum_share_interval_secs - average time between shares we aim for from each");
+	println!("                                stratum client (defaults to 10)");
+	println!("--vardiff_window_secs - how long we count a stratum client's shares for before");
+	println!("                        adjusting its difficulty (defaults to 60)");
+	println!("--max_clients - maximum number of stratum and native clients connected at once");
+	println!("--max_clients_per_ip - maximum number of clients connected at once from one IP");
+	println!("--max_msgs_per_sec - messages per second a client may send before being");
+	println!("                     disconnected (defaults to 100)");
+	println!("--ban_after_violations - number of protocol violations (including exceeding");
+	println!("                         max_msgs_per_sec) after which an IP is banned (defaults to 10)");
+	println!("--ban_secs - how long such bans last (defaults to 600)");
+	println!("--ban - an IP to refuse clients from permanently, may be given multiple times");
+	println!("        Current bans, and duplicate shares rejected per worker, are printed on SIGUSR1");
+	println!("We always try to keep exactly one connection open per argument, no matter how");
+	println!("many hosts a DNS name may resolve to. We try each hostname until one works.");
+	println!("Job providers are not prioritized (the latest job is always used), pools are");
+	println!("prioritized in the order they appear on the command line.");
+
+	let mut job_provider_hosts = Vec::new();
+	let mut pool_server_hosts = Vec::new();
+	let mut stratum_listen_binds = Vec::new();
+	let mut mining_listen_binds = Vec::new();
+	let mut stratum_listen_binds_tls = Vec::new();
+	let mut mining_listen_binds_tls = Vec::new();
+	let mut tls_cert = None;
+	let mut tls_key = None;
+	let mut stratum_credentials_file = None;
+	let mut stratum_get_transactions = None;
+	let mut mining_auth_key = None;
+	let mut payout_addr = None;
+	let mut pool_weights: Option<Vec<u64>> = None;
+	let mut pool_balance_quota = None;
+	let mut max_appended_fraction = None;
+	let mut min_remaining_fraction = None;
+	let mut allowed_scripts: Option<Vec<Script>> = None;
+	let mut no_pool_policy = None;
+	let mut no_pool_grace_secs = None;
+	let mut keepalive_interval_secs = None;
+	let mut shutdown_reconnect = None;
+	let mut drain_timeout_secs = None;
+	let mut worker_threads = None;
+	let mut stratum_max_jobs = None;
+	let mut stratum_max_job_age_secs = None;
+	let mut stratum_renotify_secs = None;
+	let mut stratum_min_difficulty = None;
+	let mut stratum_max_difficulty = None;
+	let mut stratum_share_interval_secs = None;
+	let mut vardiff_window_secs = None;
+	let mut max_clients = None;
+	let mut max_clients_per_ip = None;
+	let mut max_msgs_per_sec = None;
+	let mut ban_after_violations = None;
+	let mut ban_secs = None;
+	let mut banned_ips = Vec::new();
+	let mut socks5_proxy: Option<SocketAddr> = None;
+	let mut keepalive_timeout_secs = None;
+
+	for arg in env::args().skip(1) {
+		if arg.starts_with("--job_provider") {
+			match parse_upstream(arg.split_at(15).1) {
+				Some(upstream) => job_provider_hosts.push(upstream),
+				None => {
+					println!("Failed to parse job_provider: {}", arg);
+					return;
+				}
+			}
+		} else if arg.starts_with("--pool_server") {
+			match parse_upstream(arg.split_at(14).1) {
+				Some(upstream) => pool_server_hosts.push(upstream),
+				None => {
+					println!("Failed to parse pool_server: {}", arg);
+					return;
+				}
+			}
+		} else if arg.starts_with("--socks5_proxy") {
+			if socks5_proxy.is_some() {
+				println!("Cannot specify multiple socks5_proxy values");
 				return;
 			}
-			stratum_listen_bind = Some(match arg.split_at(22).1.parse() {
-				Ok(sockaddr) => sockaddr,
-				Err(_) =>{
-					println!("Failed to parse stratum_listen_bind into a socket address");
+			socks5_proxy = Some(match arg.split_at(15).1.parse() {
+				Ok(addr) => addr,
+				Err(_) => {
+					println!("Failed to parse socks5_proxy, it must be an IP:port");
 					return;
 				}
 			});
-		} else if arg.starts_with("--mining_listen_bind") {
-			if mining_listen_bind.is_some() {
-				println!("Cannot specify multiple listen binds");
+		} else if arg.starts_with("--stratum_listen_bind_tls") {
+			match parse_listen_bind(arg.split_at(26).1) {
+				Some(bind) => stratum_listen_binds_tls.push(bind),
+				None => {
+					println!("Failed to parse stratum_listen_bind_tls into a socket address or unix:path");
+					return;
+				}
+			}
+		} else if arg.starts_with("--mining_listen_bind_tls") {
+			match parse_listen_bind(arg.split_at(25).1) {
+				Some(bind) => mining_listen_binds_tls.push(bind),
+				None => {
+					println!("Failed to parse mining_listen_bind_tls into a socket address or unix:path");
+					return;
+				}
+			}
+		} else if arg.starts_with("--tls_cert") {
+			if tls_cert.is_some() {
+				println!("Cannot specify multiple tls_cert values");
 				return;
 			}
-			mining_listen_bind = Some(match arg.split_at(21).1.parse() {
-				Ok(sockaddr) => sockaddr,
-				Err(_) =>{
-					println!("Failed to parse mining_listen_bind into a socket address");
+			tls_cert = Some(arg.split_at(11).1.to_string());
+		} else if arg.starts_with("--tls_key") {
+			if tls_key.is_some() {
+				println!("Cannot specify multiple tls_key values");
+				return;
+			}
+			tls_key = Some(arg.split_at(10).1.to_string());
+		} else if arg.starts_with("--stratum_credentials_file") {
+			if stratum_credentials_file.is_some() {
+				println!("Cannot specify multiple stratum_credentials_file values");
+				return;
+			}
+			stratum_credentials_file = Some(arg.split_at(27).1.to_string());
+		} else if arg.starts_with("--stratum_get_transactions") {
+			if stratum_get_transactions.is_some() {
+				println!("Cannot specify multiple stratum_get_transactions values");
+				return;
+			}
+			stratum_get_transactions = Some(match arg.split_at(27).1 {
+				"txids" => TransactionsFormat::Txids,
+				"hex" => TransactionsFormat::Hex,
+				_ => {
+					println!("stratum_get_transactions must be one of txids or hex");
 					return;
 				}
 			});
+		} else if arg.starts_with("--stratum_listen_bind") {
+			match parse_listen_bind(arg.split_at(22).1) {
+				Some(bind) => stratum_listen_binds.push(bind),
+				None => {
+					println!("Failed to parse stratum_listen_bind into a socket address or unix:path");
+					return;
+				}
+			}
+		} else if arg.starts_with("--mining_listen_bind") {
+			match parse_listen_bind(arg.split_at(21).1) {
+				Some(bind) => mining_listen_binds.push(bind),
+				None => {
+					println!("Failed to parse mining_listen_bind into a socket address or unix:path");
+					return;
+				}
+			}
 		} else if arg.starts_with("--mining_auth_key") {
 			if mining_auth_key.is_some() {
 				println!("Cannot specify multiple auth keys");
@@ -906,6 +2294,326 @@ fn main() {
 					return;
 				}
 			});
+		} else if arg.starts_with("--pool_weights") {
+			if pool_weights.is_some() {
+				println!("Cannot specify multiple pool_weights");
+				return;
+			}
+			let mut weights = Vec::new();
+			for weight in arg.split_at(15).1.split(',') {
+				match weight.parse() {
+					Ok(0) | Err(_) => {
+						println!("Failed to parse pool_weights into a list of positive integers");
+						return;
+					},
+					Ok(w) => weights.push(w),
+				}
+			}
+			pool_weights = Some(weights);
+		} else if arg.starts_with("--pool_balance") {
+			if pool_balance_quota.is_some() {
+				println!("Cannot specify multiple pool_balance settings");
+				return;
+			}
+			let setting = arg.split_at(15).1;
+			pool_balance_quota = Some(if setting.starts_with("time:") {
+				match setting.split_at(5).1.parse() {
+					Ok(0) | Err(_) => {
+						println!("Failed to parse pool_balance time slice into a positive number of seconds");
+						return;
+					},
+					Ok(secs) => BalanceQuota::Time(Duration::from_secs(secs)),
+				}
+			} else if setting.starts_with("shares:") {
+				match setting.split_at(7).1.parse() {
+					Ok(0) | Err(_) => {
+						println!("Failed to parse pool_balance share quota into a positive number of shares");
+						return;
+					},
+					Ok(shares) => BalanceQuota::Shares(shares),
+				}
+			} else {
+				println!("pool_balance must be either time:seconds or shares:count");
+				return;
+			});
+		} else if arg.starts_with("--pool_max_appended_fraction") {
+			if max_appended_fraction.is_some() {
+				println!("Cannot specify multiple pool_max_appended_fraction values");
+				return;
+			}
+			max_appended_fraction = Some(match arg.split_at(29).1.parse::<f64>() {
+				Ok(fraction) if fraction >= 0.0 && fraction <= 1.0 => fraction,
+				_ => {
+					println!("Failed to parse pool_max_appended_fraction into a number between 0 and 1");
+					return;
+				}
+			});
+		} else if arg.starts_with("--pool_min_remaining_fraction") {
+			if min_remaining_fraction.is_some() {
+				println!("Cannot specify multiple pool_min_remaining_fraction values");
+				return;
+			}
+			min_remaining_fraction = Some(match arg.split_at(30).1.parse::<f64>() {
+				Ok(fraction) if fraction >= 0.0 && fraction <= 1.0 => fraction,
+				_ => {
+					println!("Failed to parse pool_min_remaining_fraction into a number between 0 and 1");
+					return;
+				}
+			});
+		} else if arg.starts_with("--pool_allowed_script") {
+			let script_str = arg.split_at(22).1;
+			let script = match Address::from_str(script_str) {
+				Ok(addr) => addr.script_pubkey(),
+				Err(_) => match utils::hex_to_vec(script_str) {
+					Some(bytes) => Script::from(bytes),
+					None => {
+						println!("Failed to parse pool_allowed_script into an address or hex script");
+						return;
+					}
+				}
+			};
+			if allowed_scripts.is_none() {
+				allowed_scripts = Some(Vec::new());
+			}
+			allowed_scripts.as_mut().unwrap().push(script);
+		} else if arg.starts_with("--no_pool_policy") {
+			if no_pool_policy.is_some() {
+				println!("Cannot specify multiple no_pool_policy values");
+				return;
+			}
+			no_pool_policy = Some(match arg.split_at(17).1 {
+				"keep" => NoPoolPolicy::KeepLastPayout,
+				"solo" => NoPoolPolicy::Solo,
+				"stop" => NoPoolPolicy::StopWork,
+				_ => {
+					println!("no_pool_policy must be one of keep, solo or stop");
+					return;
+				}
+			});
+		} else if arg.starts_with("--no_pool_grace_secs") {
+			if no_pool_grace_secs.is_some() {
+				println!("Cannot specify multiple no_pool_grace_secs values");
+				return;
+			}
+			no_pool_grace_secs = Some(match arg.split_at(21).1.parse() {
+				Ok(secs) => secs,
+				Err(_) => {
+					println!("Failed to parse no_pool_grace_secs into a number of seconds");
+					return;
+				}
+			});
+		} else if arg.starts_with("--shutdown_reconnect") {
+			if shutdown_reconnect.is_some() {
+				println!("Cannot specify multiple shutdown_reconnect values");
+				return;
+			}
+			shutdown_reconnect = match socks5::split_host_port(arg.split_at(21).1) {
+				Some(host_port) => Some(host_port),
+				None => {
+					println!("Failed to parse shutdown_reconnect, it must be host:port");
+					return;
+				}
+			};
+		} else if arg.starts_with("--drain_timeout_secs") {
+			if drain_timeout_secs.is_some() {
+				println!("Cannot specify multiple drain_timeout_secs values");
+				return;
+			}
+			drain_timeout_secs = Some(match arg.split_at(21).1.parse() {
+				Ok(secs) => secs,
+				Err(_) => {
+					println!("Failed to parse drain_timeout_secs into a number of seconds");
+					return;
+				}
+			});
+		} else if arg.starts_with("--worker_threads") {
+			if worker_threads.is_some() {
+				println!("Cannot specify multiple worker_threads values");
+				return;
+			}
+			worker_threads = Some(match arg.split_at(17).1.parse() {
+				Ok(threads) if threads > 0 && threads <= u16::max_value() as usize => threads,
+				_ => {
+					println!("Failed to parse worker_threads into a non-zero number of threads");
+					return;
+				}
+			});
+		} else if arg.starts_with("--stratum_max_jobs") {
+			if stratum_max_jobs.is_some() {
+				println!("Cannot specify multiple stratum_max_jobs values");
+				return;
+			}
+			stratum_max_jobs = Some(match arg.split_at(19).1.parse() {
+				Ok(count) if count > 0 => count,
+				_ => {
+					println!("Failed to parse stratum_max_jobs into a non-zero number of jobs");
+					return;
+				}
+			});
+		} else if arg.starts_with("--stratum_max_job_age_secs") {
+			if stratum_max_job_age_secs.is_some() {
+				println!("Cannot specify multiple stratum_max_job_age_secs values");
+				return;
+			}
+			stratum_max_job_age_secs = Some(match arg.split_at(27).1.parse() {
+				Ok(secs) => secs,
+				Err(_) => {
+					println!("Failed to parse stratum_max_job_age_secs into a number of seconds");
+					return;
+				}
+			});
+		} else if arg.starts_with("--stratum_renotify_secs") {
+			if stratum_renotify_secs.is_some() {
+				println!("Cannot specify multiple stratum_renotify_secs values");
+				return;
+			}
+			stratum_renotify_secs = Some(match arg.split_at(24).1.parse() {
+				Ok(secs) if secs > 0 => secs,
+				_ => {
+					println!("Failed to parse stratum_renotify_secs into a non-zero number of seconds");
+					return;
+				}
+			});
+		} else if arg.starts_with("--stratum_min_difficulty") {
+			if stratum_min_difficulty.is_some() {
+				println!("Cannot specify multiple stratum_min_difficulty values");
+				return;
+			}
+			stratum_min_difficulty = Some(match arg.split_at(25).1.parse::<f64>() {
+				Ok(diff) if diff > 0.0 && diff.is_finite() => diff,
+				_ => {
+					println!("Failed to parse stratum_min_difficulty into a positive difficulty");
+					return;
+				}
+			});
+		} else if arg.starts_with("--stratum_max_difficulty") {
+			if stratum_max_difficulty.is_some() {
+				println!("Cannot specify multiple stratum_max_difficulty values");
+				return;
+			}
+			stratum_max_difficulty = Some(match arg.split_at(25).1.parse::<f64>() {
+				Ok(diff) if diff > 0.0 => diff,
+				_ => {
+					println!("Failed to parse stratum_max_difficulty into a positive difficulty");
+					return;
+				}
+			});
+		} else if arg.starts_with("--stratum_share_interval_secs") {
+			if stratum_share_interval_secs.is_some() {
+				println!("Cannot specify multiple stratum_share_interval_secs values");
+				return;
+			}
+			stratum_share_interval_secs = Some(match arg.split_at(30).1.parse() {
+				Ok(secs) if secs > 0 => secs,
+				_ => {
+					println!("Failed to parse stratum_share_interval_secs into a non-zero number of seconds");
+					return;
+				}
+			});
+		} else if arg.starts_with("--vardiff_window_secs") {
+			if vardiff_window_secs.is_some() {
+				println!("Cannot specify multiple vardiff_window_secs values");
+				return;
+			}
+			vardiff_window_secs = Some(match arg.split_at(22).1.parse() {
+				Ok(secs) if secs > 0 => secs,
+				_ => {
+					println!("Failed to parse vardiff_window_secs into a non-zero number of seconds");
+					return;
+				}
+			});
+		} else if arg.starts_with("--max_clients_per_ip") {
+			if max_clients_per_ip.is_some() {
+				println!("Cannot specify multiple max_clients_per_ip values");
+				return;
+			}
+			max_clients_per_ip = Some(match arg.split_at(21).1.parse() {
+				Ok(count) if count > 0 => count,
+				_ => {
+					println!("Failed to parse max_clients_per_ip into a non-zero number of clients");
+					return;
+				}
+			});
+		} else if arg.starts_with("--max_clients") {
+			if max_clients.is_some() {
+				println!("Cannot specify multiple max_clients values");
+				return;
+			}
+			max_clients = Some(match arg.split_at(14).1.parse() {
+				Ok(count) if count > 0 => count,
+				_ => {
+					println!("Failed to parse max_clients into a non-zero number of clients");
+					return;
+				}
+			});
+		} else if arg.starts_with("--max_msgs_per_sec") {
+			if max_msgs_per_sec.is_some() {
+				println!("Cannot specify multiple max_msgs_per_sec values");
+				return;
+			}
+			max_msgs_per_sec = Some(match arg.split_at(19).1.parse() {
+				Ok(count) if count > 0 => count,
+				_ => {
+					println!("Failed to parse max_msgs_per_sec into a non-zero number of messages");
+					return;
+				}
+			});
+		} else if arg.starts_with("--ban_after_violations") {
+			if ban_after_violations.is_some() {
+				println!("Cannot specify multiple ban_after_violations values");
+				return;
+			}
+			ban_after_violations = Some(match arg.split_at(23).1.parse() {
+				Ok(count) if count > 0 => count,
+				_ => {
+					println!("Failed to parse ban_after_violations into a non-zero number of violations");
+					return;
+				}
+			});
+		} else if arg.starts_with("--ban_secs") {
+			if ban_secs.is_some() {
+				println!("Cannot specify multiple ban_secs values");
+				return;
+			}
+			ban_secs = Some(match arg.split_at(11).1.parse() {
+				Ok(secs) => secs,
+				Err(_) => {
+					println!("Failed to parse ban_secs into a number of seconds");
+					return;
+				}
+			});
+		} else if arg.starts_with("--ban=") {
+			match arg.split_at(6).1.parse::<IpAddr>() {
+				Ok(ip) => banned_ips.push(ip),
+				Err(_) => {
+					println!("Failed to parse ban into an IP address");
+					return;
+				}
+			}
+		} else if arg.starts_with("--keepalive_interval_secs") {
+			if keepalive_interval_secs.is_some() {
+				println!("Cannot specify multiple keepalive_interval_secs values");
+				return;
+			}
+			keepalive_interval_secs = Some(match arg.split_at(26).1.parse() {
+				Ok(secs) if secs > 0 => secs,
+				_ => {
+					println!("Failed to parse keepalive_interval_secs into a non-zero number of seconds");
+					return;
+				}
+			});
+		} else if arg.starts_with("--keepalive_timeout_secs") {
+			if keepalive_timeout_secs.is_some() {
+				println!("Cannot specify multiple keepalive_timeout_secs values");
+				return;
+			}
+			keepalive_timeout_secs = Some(match arg.split_at(25).1.parse() {
+				Ok(secs) => secs,
+				Err(_) => {
+					println!("Failed to parse keepalive_timeout_secs into a number of seconds");
+					return;
+				}
+			});
 		} else {
 			println!("Unkown arg: {}", arg);
 			return;
@@ -916,50 +2624,197 @@ fn main() {
 		println!("Need at least some job providers");
 		return;
 	}
-	if stratum_listen_bind.is_none() && mining_listen_bind.is_none() {
+	for upstream in job_provider_hosts.iter_mut().chain(pool_server_hosts.iter_mut()) {
+		if upstream.proxy.is_none() {
+			upstream.proxy = socks5_proxy;
+		}
+		// Hosts behind a proxy may only be resolvable by the proxy (eg .onion hosts)
+		if upstream.proxy.is_none() && !upstream.host.starts_with("unix:") && upstream.host.to_socket_addrs().is_err() {
+			println!("Bad address resolution: {}", upstream.host);
+			return;
+		}
+	}
+	let drain_timeout = Duration::from_secs(drain_timeout_secs.unwrap_or(30));
+	let keepalive_config = KeepaliveConfig {
+		interval: Duration::from_secs(keepalive_interval_secs.unwrap_or(30)),
+		timeout: Duration::from_secs(keepalive_timeout_secs.unwrap_or(90)),
+	};
+	if keepalive_config.timeout <= keepalive_config.interval {
+		println!("keepalive_timeout_secs must be greater than keepalive_interval_secs");
+		return;
+	}
+	let stratum_enabled = !stratum_listen_binds.is_empty() || !stratum_listen_binds_tls.is_empty();
+	let mining_enabled = !mining_listen_binds.is_empty() || !mining_listen_binds_tls.is_empty();
+	if !stratum_enabled && !mining_enabled {
 		println!("Need some listen bind");
 		return;
 	}
+	let tls_acceptor = if !stratum_listen_binds_tls.is_empty() || !mining_listen_binds_tls.is_empty() {
+		if tls_cert.is_none() || tls_key.is_none() {
+			println!("Need tls_cert and tls_key for TLS listen binds");
+			return;
+		}
+		match tls::load_acceptor(tls_cert.as_ref().unwrap(), tls_key.as_ref().unwrap()) {
+			Ok(acceptor) => Some(acceptor),
+			Err(e) => {
+				println!("{}", e);
+				return;
+			}
+		}
+	} else { None };
+	let worker_verifier: Arc<WorkerVerifier> = match stratum_credentials_file {
+		Some(path) => match CredentialsFile::load(&path) {
+			Ok(creds) => Arc::new(creds),
+			Err(e) => {
+				println!("Failed to load stratum_credentials_file: {}", e);
+				return;
+			}
+		},
+		None => Arc::new(AllowAllWorkers),
+	};
 	if payout_addr.is_none() {
 		println!("Need some payout address");
 		return;
 	}
-	if mining_listen_bind.is_some() && mining_auth_key.is_none() {
+	if mining_enabled && mining_auth_key.is_none() {
 		println!("Need some mining_auth_key for mining_listen_bind");
 		return;
 	}
+	if pool_weights.is_some() && pool_weights.as_ref().unwrap().len() != pool_server_hosts.len() {
+		println!("Need exactly one pool_weight per pool_server");
+		return;
+	}
+	if pool_balance_quota.is_some() && pool_weights.is_none() {
+		println!("pool_balance requires pool_weights");
+		return;
+	}
+	let payout_policy = Rc::new(PayoutPolicy {
+		max_appended_fraction,
+		allowed_scripts,
+		min_remaining_fraction,
+	});
+	let pool_balance_quota = pool_balance_quota.unwrap_or(BalanceQuota::Time(Duration::from_secs(60)));
+
+	let worker_threads = worker_threads.unwrap_or_else(|| {
+		thread::available_parallelism().map(|threads| cmp::min(threads.get(), u16::max_value() as usize)).unwrap_or(1)
+	});
+
+	let default_retention = JobRetention::default();
+	let job_retention = JobRetention {
+		max_jobs: stratum_max_jobs.unwrap_or(default_retention.max_jobs),
+		max_age: stratum_max_job_age_secs.map(Duration::from_secs).unwrap_or(default_retention.max_age),
+	};
+	let default_vardiff = VardiffConfig::default();
+	if let (Some(min), Some(max)) = (stratum_min_difficulty, stratum_max_difficulty) {
+		if min > max {
+			println!("stratum_min_difficulty must not be greater than stratum_max_difficulty");
+			return;
+		}
+	}
+	let stratum_config = StratumConfig {
+		job_retention: job_retention,
+		renotify_interval: Duration::from_secs(stratum_renotify_secs.unwrap_or(30)),
+		vardiff: VardiffConfig {
+			share_interval: stratum_share_interval_secs.map(Duration::from_secs).unwrap_or(default_vardiff.share_interval),
+			retarget_window: vardiff_window_secs.map(Duration::from_secs).unwrap_or(default_vardiff.retarget_window),
+			min_difficulty: stratum_min_difficulty.unwrap_or(default_vardiff.min_difficulty),
+			max_difficulty: stratum_max_difficulty.unwrap_or(default_vardiff.max_difficulty),
+		},
+		worker_verifier: worker_verifier,
+		transactions_format: stratum_get_transactions.unwrap_or(TransactionsFormat::Txids),
+	};
+
+	let default_limits = LimitsConfig::default();
+	let client_limiter = ClientLimiter::new(LimitsConfig {
+		max_clients: max_clients,
+		max_clients_per_ip: max_clients_per_ip,
+		max_msgs_per_sec: max_msgs_per_sec.unwrap_or(default_limits.max_msgs_per_sec),
+		ban_after_violations: ban_after_violations.unwrap_or(default_limits.ban_after_violations),
+		ban_duration: ban_secs.map(Duration::from_secs).unwrap_or(default_limits.ban_duration),
+	});
+	for ip in banned_ips {
+		client_limiter.lock().unwrap().ban(ip, None);
+	}
 
 	unsafe {
 		TIMER = Some(tokio_timer::Timer::default());
 	}
 
+	let stratum_clients = Arc::new(AtomicUsize::new(0));
+	let duplicate_shares = DuplicateShareCounts::new();
+	let mining_clients = Arc::new(AtomicUsize::new(0));
+	let mut workers = Vec::with_capacity(worker_threads);
+	for worker_id in 0..worker_threads {
+		let (stratum_jobs, stratum_job_rx) = if stratum_enabled {
+			let (job_tx, job_rx) = sync_mpsc::channel(5);
+			(Some(job_tx), Some((job_rx, stratum_config.clone())))
+		} else { (None, None) };
+		let (mining_jobs, mining_job_rx) = if mining_enabled {
+			let (job_tx, job_rx) = sync_mpsc::channel(5);
+			(Some(job_tx), Some((job_rx, mining_auth_key.unwrap(), job_retention)))
+		} else { (None, None) };
+		let (command_tx, command_rx) = sync_mpsc::unbounded();
+		let stratum_clients = stratum_clients.clone();
+		let mining_clients = mining_clients.clone();
+		let duplicate_shares = duplicate_shares.clone();
+		thread::spawn(move || {
+			run_worker(worker_id as u16, stratum_job_rx, mining_job_rx, command_rx, stratum_clients, mining_clients, duplicate_shares);
+		});
+		workers.push((stratum_jobs, mining_jobs, command_tx));
+	}
+
 	let (job_tx, job_rx) = mpsc::channel(5);
 	let cur_work_rc = Rc::new(RefCell::new(JobInfo {
 		payout_script: payout_addr.clone().unwrap().script_pubkey(),
+		payout_policy: payout_policy.clone(),
 		cur_job: None,
 		cur_job_source: None,
 		cur_pool: None,
 		cur_pool_source: None,
+		pools: Vec::new(),
+		pool_links: Vec::new(),
+		job_provider_links: Vec::new(),
+		balancer: match pool_weights {
+			Some(weights) => Some(PoolBalancer::new(pool_balance_quota, weights)),
+			None => None,
+		},
+
+		no_pool_policy: no_pool_policy.unwrap_or(NoPoolPolicy::Solo),
+		no_pool_grace: Duration::from_secs(no_pool_grace_secs.unwrap_or(60)),
+		all_pools_lost_since: None,
+		no_pool_fallback_active: false,
+		no_pool_fallbacks: 0,
+		work_stopped: false,
+		last_status_report: Instant::now(),
+
 		job_tx: job_tx,
 	}));
 
 	current_thread::block_on_all(future::lazy(|| -> future::FutureResult<(), ()> {
-		for host in job_provider_hosts {
+		let mut job_providers = Vec::new();
+		for upstream in job_provider_hosts {
 			let (mut handler, mut job_rx) = JobProviderHandler::new(None);
+			job_providers.push(handler.clone());
 			let work_rc = cur_work_rc.clone();
 			let handler_rc = handler.clone();
 			current_thread::spawn(job_rx.for_each(move |job| {
 				let mut cur_work = work_rc.borrow_mut();
 				if cur_work.cur_job.is_none() || cur_work.cur_job.as_ref().unwrap().0.template_id < job.0.template_id {
 					let new_job = Some(job);
-					match merge_job_pool(cur_work.payout_script.clone(), &new_job, Some(handler_rc.clone()), &cur_work.cur_pool, cur_work.cur_pool_source.clone()) {
+					let mut work = merge_job_pool(cur_work.payout_script.clone(), &cur_work.payout_policy, &new_job, Some(handler_rc.clone()), &cur_work.cur_pool, cur_work.cur_pool_source.clone());
+					if work.is_none() && cur_work.cur_pool.is_some() {
+						// Rather than leave miners on an old job, pay ourselves if the pool's payout
+						// info cannot be used with this job.
+						println!("Failed to use pool payout info with new job, mining it with our own payout address");
+						work = merge_job_pool(cur_work.payout_script.clone(), &cur_work.payout_policy, &new_job, Some(handler_rc.clone()), &None, None);
+						if work.is_some() {
+							cur_work.cur_pool_source.as_ref().unwrap().borrow_mut().failed_over_until = Some(Instant::now() + POOL_REJECT_FAILOVER_TIME);
+							cur_work.set_cur_pool(None, None);
+						}
+					}
+					match work {
 						Some(work) => {
-							match cur_work.job_tx.start_send(work) {
-								Ok(_) => {},
-								Err(_) => {
-									println!("Job provider is providing work faster than we can process it");
-								}
-							}
+							cur_work.announce_work(work);
 							cur_work.cur_job = new_job;
 							cur_work.cur_job_source = Some(handler_rc.clone());
 						},
@@ -970,99 +2825,236 @@ fn main() {
 			}).then(|_| {
 				future::result(Ok(()))
 			}));
-			ConnectionMaintainer::make_connection(Rc::new(RefCell::new(ConnectionMaintainer::new(host, handler))));
+			let host = upstream.host.clone();
+			let maintainer = ConnectionMaintainer::new(upstream, handler, keepalive_config);
+			cur_work_rc.borrow_mut().job_provider_links.push((host, maintainer.get_health()));
+			ConnectionMaintainer::make_connection(Rc::new(RefCell::new(maintainer)));
 		}
 
-		for (idx, host) in pool_server_hosts.iter().enumerate() {
-			let (mut handler, mut pool_rx) = PoolHandler::new(None, payout_addr.as_ref().unwrap().clone(), idx);
+		for (idx, upstream) in pool_server_hosts.iter().enumerate() {
+			let (mut handler, mut pool_rx) = PoolHandler::new(None, payout_addr.as_ref().unwrap().clone(), idx, payout_policy.clone());
 			let work_rc = cur_work_rc.clone();
 			let handler_rc = handler.clone();
+			cur_work_rc.borrow_mut().pools.push(handler.clone());
 			current_thread::spawn(pool_rx.for_each(move |pool_info| {
 				let mut cur_work = work_rc.borrow_mut();
+				if !handler_rc.borrow().is_usable() {
+					return future::result(Ok(()));
+				}
 				match cur_work.cur_pool_source {
 					Some(ref cur_pool) => {
 						let pool = cur_pool.borrow();
-						//TODO: Fallback to lower-priority pool when one gets disconnected
-						if pool.is_connected() && pool.get_priority() < handler_rc.borrow().get_priority() {
+						if cur_work.balancer.is_some() {
+							// The balancer picks which pool to move to when the current slice ends
+							if pool.is_usable() && !Rc::ptr_eq(cur_pool, &handler_rc) {
+								return future::result(Ok(()));
+							}
+						} else if pool.is_usable() && pool.get_priority() < handler_rc.borrow().get_priority() {
 							return future::result(Ok(()));
 						}
 					},
 					None => {}
 				}
-				let new_pool = Some(pool_info);
-				match merge_job_pool(cur_work.payout_script.clone(), &cur_work.cur_job, cur_work.cur_job_source.clone(), &new_pool, Some(handler_rc.clone())) {
-					Some(work) => {
-						match cur_work.job_tx.start_send(work) {
-							Ok(_) => {},
-							Err(_) => {
-								println!("Job provider is providing work faster than we can process it");
-							}
-						}
-						cur_work.cur_pool = new_pool;
-						cur_work.cur_pool_source = Some(handler_rc.clone());
-					},
-					None => {
-						if cur_work.cur_job.is_none() {
-							cur_work.cur_pool = new_pool;
-							cur_work.cur_pool_source = Some(handler_rc.clone());
-						}
-					}
-				}
+				cur_work.use_pool(pool_info, handler_rc.clone());
 				future::result(Ok(()))
 			}).then(|_| {
 				future::result(Ok(()))
 			}));
-			ConnectionMaintainer::make_connection(Rc::new(RefCell::new(ConnectionMaintainer::new(host.clone(), handler))));
+			let maintainer = ConnectionMaintainer::new(upstream.clone(), handler, keepalive_config);
+			cur_work_rc.borrow_mut().pool_links.push(maintainer.get_health());
+			ConnectionMaintainer::make_connection(Rc::new(RefCell::new(maintainer)));
+		}
+
+		let work_rc = cur_work_rc.clone();
+		let timer: &Timer = unsafe { TIMER.as_ref().unwrap() };
+		current_thread::spawn(timer.interval(Duration::from_secs(1)).for_each(move |_| {
+			let mut cur_work = work_rc.borrow_mut();
+			cur_work.check_all_pools_lost();
+			if cur_work.balancer.is_some() {
+				cur_work.check_pool_balance();
+			} else {
+				cur_work.check_pool_failover();
+			}
+			if cur_work.last_status_report.elapsed() >= STATUS_REPORT_INTERVAL {
+				cur_work.report_status();
+				cur_work.last_status_report = Instant::now();
+			}
+			future::result(Ok(()))
+		}).then(|_| {
+			future::result(Ok(()))
+		}));
+
+		let listener_stops = Rc::new(RefCell::new(Vec::new()));
+		let worker_commands: Vec<_> = workers.iter().map(|&(_, _, ref command_tx)| command_tx.clone()).collect();
+		macro_rules! handle_incoming {
+			($incoming: expr, $server_type: expr, $acceptor: expr) => {
+				let acceptor: Option<tokio_tls::TlsAcceptor> = $acceptor;
+				let worker_commands = worker_commands.clone();
+				let mut next_worker = 0;
+				let (stop_tx, stop_rx) = oneshot::channel();
+				listener_stops.borrow_mut().push(stop_tx);
+				let limiter = client_limiter.clone();
+				current_thread::spawn($incoming.for_each(move |(sock, ip)| {
+					let slot = match ClientLimiter::try_accept(&limiter, ip) {
+						Ok(slot) => slot,
+						Err(reason) => {
+							match ip {
+								Some(ip) => println!("Refused client connection from {}: {:?}", ip, reason),
+								None => println!("Refused client connection: {:?}", reason),
+							}
+							return future::result(Ok(()));
+						}
+					};
+					// Hand clients out round-robin, leaving all the work of serving them to the workers
+					match worker_commands[next_worker].unbounded_send(WorkerCommand::NewConnection(sock, slot, $server_type, acceptor.clone())) {
+						Ok(_) => {},
+						Err(_) => println!("Worker {} died, dropping new client", next_worker),
+					}
+					next_worker = (next_worker + 1) % worker_commands.len();
+					future::result(Ok(()))
+				}).select(stop_rx.then(|_| -> Result<(), io::Error> {
+					Ok(())
+				})).then(|_| {
+					future::result(Ok(()))
+				}));
+			}
 		}
 
 		macro_rules! bind_and_handle {
-			($listen_bind_option: expr, $server: expr, $server_type: tt) => {
-				match $listen_bind_option {
-					Some(listen_bind) => {
-						let server = $server;
-						match net::TcpListener::bind(&listen_bind) {
-							Ok(listener) => {
-								current_thread::spawn(listener.incoming().for_each(move |sock| {
-									$server_type::new_connection(server.clone(), sock);
-									future::result(Ok(()))
-								}).then(|_| {
-									future::result(Ok(()))
-								}));
+			($listen_binds: expr, $tls_listen_binds: expr, $server_type: expr) => {
+				let plain_binds = $listen_binds.iter().map(|bind| (bind, None));
+				let tls_binds = $tls_listen_binds.iter().map(|bind| (bind, tls_acceptor.clone()));
+				for (listen_bind, acceptor) in plain_binds.chain(tls_binds) {
+					match listen_bind {
+						&ListenBind::Tcp(ref addr) => match net::TcpListener::bind(addr) {
+							Ok(mut listener) => {
+								handle_incoming!(stream::poll_fn(move || {
+									listener.poll_accept_std().map(|res| res.map(|(sock, addr)| Some((AcceptedStream::Tcp(sock), Some(addr.ip())))))
+								}), $server_type, acceptor);
 							},
 							Err(_) => {
-								println!("Failed to bind to listen bind addr");
+								println!("Failed to bind to listen bind addr {}", addr);
 								return future::result(Ok(()));
 							}
-						};
-					},
-					None => {},
+						},
+						&ListenBind::Unix(ref path) => match bind_unix(path) {
+							Ok(listener) => {
+								handle_incoming!(stream::poll_fn(move || {
+									listener.poll_accept_std().map(|res| res.map(|(sock, _)| Some((AcceptedStream::Unix(sock), None))))
+								}), $server_type, acceptor);
+							},
+							Err(e) => {
+								println!("Failed to bind to listen socket {}: {}", path.display(), e);
+								return future::result(Ok(()));
+							}
+						},
+					}
 				}
 			}
 		}
+		bind_and_handle!(stratum_listen_binds, stratum_listen_binds_tls, ServerType::Stratum);
+		bind_and_handle!(mining_listen_binds, mining_listen_binds_tls, ServerType::Mining);
+
+		let mut workers_jobs: Vec<_> = workers.iter().map(|&(ref stratum_jobs, ref mining_jobs, _)| (stratum_jobs.clone(), mining_jobs.clone())).collect();
+		current_thread::spawn(job_rx.for_each(move |job| {
+			for (worker_id, &mut (ref mut stratum_jobs, ref mut mining_jobs)) in workers_jobs.iter_mut().enumerate() {
+				if let &mut Some(ref mut mining_tx) = mining_jobs {
+					match mining_tx.try_send(job.clone()) {
+						Ok(_) => {},
+						Err(_) => { println!("Dropped new job for native clients on worker {} as it ran behind!", worker_id); },
+					}
+				}
+				if let &mut Some(ref mut stratum_tx) = stratum_jobs {
+					match stratum_tx.try_send(job.clone()) {
+						Ok(_) => {},
+						Err(_) => { println!("Dropped new job for stratum clients on worker {} as it ran behind!", worker_id); },
+					}
+				}
+			}
+			future::result(Ok(()))
+		}).then(|_| {
+			future::result(Ok(()))
+		}));
 
-		if stratum_listen_bind.is_some() && mining_listen_bind.is_none() {
-			bind_and_handle!(stratum_listen_bind, StratumServer::new(job_rx), StratumServer);
-		} else if stratum_listen_bind.is_none() && mining_listen_bind.is_some() {
-			bind_and_handle!(mining_listen_bind, MiningServer::new(job_rx, mining_auth_key.unwrap()), MiningServer);
-		} else {
-			let (mut stratum_tx, stratum_rx) = mpsc::channel(5);
-			let (mut mining_tx, mining_rx) = mpsc::channel(5);
-			current_thread::spawn(job_rx.for_each(move |job| {
-				match mining_tx.start_send(job.clone()) {
-					Ok(_) => {},
-					Err(_) => { println!("Dropped new job for native clients as server ran behind!"); },
+		let limiter = client_limiter.clone();
+		current_thread::spawn(tokio_signal::unix::Signal::new(tokio_signal::unix::SIGUSR1).flatten_stream().for_each(move |_| {
+			let bans = limiter.lock().unwrap().list_bans();
+			println!("{} IPs currently banned", bans.len());
+			for (ip, remaining) in bans {
+				match remaining {
+					Some(remaining) => println!("  {} for another {} secs", ip, remaining.as_secs()),
+					None => println!("  {} permanently", ip),
 				}
-				match stratum_tx.start_send(job) {
-					Ok(_) => {},
-					Err(_) => { println!("Dropped new job for stratum clients as server ran behind!"); },
+			}
+			let duplicates = duplicate_shares.get_counts();
+			println!("{} workers have submitted duplicate shares", duplicates.len());
+			for (worker, count) in duplicates {
+				println!("  {}: {}", worker, count);
+			}
+			future::result(Ok(()))
+		}).then(|_| {
+			future::result(Ok(()))
+		}));
+
+		let sigterm = tokio_signal::unix::Signal::new(tokio_signal::unix::SIGTERM).flatten_stream();
+		let sigint = tokio_signal::unix::Signal::new(tokio_signal::unix::SIGINT).flatten_stream();
+		let mut draining = false;
+		current_thread::spawn(sigterm.select(sigint).for_each(move |_| {
+			if draining {
+				println!("Got a second shutdown signal, exiting without waiting for the drain to complete");
+				std::process::exit(1);
+			}
+			draining = true;
+
+			println!("Shutting down, no longer accepting new connections...");
+			for stop in listener_stops.borrow_mut().drain(..) {
+				let _ = stop.send(());
+			}
+			let reconnect_sent = match (stratum_enabled, &shutdown_reconnect) {
+				(true, &Some((ref host, port))) => {
+					println!("Asking stratum clients to reconnect to {}:{}", host, port);
+					for &(_, _, ref command_tx) in workers.iter() {
+						let _ = command_tx.unbounded_send(WorkerCommand::SendReconnect(host.clone(), port));
+					}
+					true
+				},
+				_ => false,
+			};
+
+			// Wait for winning nonces to be written out to job providers, for pools to acknowledge
+			// shares already sent and, if we asked them to leave, for stratum clients to disconnect.
+			// We always wait at least one tick so that solutions workers already found get handled.
+			let drain_start = Instant::now();
+			let work_rc = cur_work_rc.clone();
+			let job_providers = job_providers.clone();
+			let stratum_clients = stratum_clients.clone();
+			let mining_clients = mining_clients.clone();
+			let timer: &Timer = unsafe { TIMER.as_ref().unwrap() };
+			current_thread::spawn(timer.interval(Duration::from_secs(1)).for_each(move |_| {
+				let cur_work = work_rc.borrow();
+				let pending_nonces: u64 = job_providers.iter().map(|provider| provider.borrow().pending_nonces()).sum();
+				let pending_shares: u64 = cur_work.pools.iter().map(|pool| pool.borrow().pending_shares()).sum();
+				let stratum_clients = stratum_clients.load(Ordering::Acquire);
+				let mining_clients = mining_clients.load(Ordering::Acquire);
+				let drained = pending_nonces == 0 && pending_shares == 0 && (!reconnect_sent || stratum_clients == 0);
+				if drained || drain_start.elapsed() >= drain_timeout {
+					if !drained {
+						println!("Drain timed out with {} winning nonces unsent, {} shares unacknowledged by pools and {} stratum clients still connected",
+							pending_nonces, pending_shares, stratum_clients);
+					}
+					cur_work.report_status();
+					println!("Shut down after draining for {} seconds with {} stratum and {} native clients connected",
+						drain_start.elapsed().as_secs(), stratum_clients, mining_clients);
+					std::process::exit(0);
 				}
 				future::result(Ok(()))
 			}).then(|_| {
 				future::result(Ok(()))
 			}));
-			bind_and_handle!(stratum_listen_bind, StratumServer::new(stratum_rx), StratumServer);
-			bind_and_handle!(mining_listen_bind, MiningServer::new(mining_rx, mining_auth_key.unwrap()), MiningServer);
-		}
+			future::result(Ok(()))
+		}).then(|_| {
+			future::result(Ok(()))
+		}));
 
 		future::result(Ok(()))
 	})).unwrap();
@@ -1,4 +1,6 @@
-use msg_framing::{BlockTemplate,BlockTemplateHeader,CoinbasePrefixPostfix,WinningNonce,WorkInfo,WorkMessage,WorkMsgFramer};
+use msg_framing::{BlockTemplate,BlockTemplateHeader,CoinbasePrefixPostfix,WinningNonce,WorkInfo,WorkMessage,WorkMsgFramer,WORK_FLAG_KEEPALIVE};
+use limits::ClientSlot;
+use shares::{DuplicateShareCounts,JobRetention,SubmittedShares};
 use utils
 ;
 use bitcoin::blockdata::block::BlockHeader;
@@ -15,12 +17,12 @@ use crypto::sha2::Sha256;
 
 use futures::{future,Stream,Sink};
 use futures::future::Future;
+use futures::sync::mpsc as sync_mpsc;
 use futures::unsync::mpsc;
 
 use tokio::executor::current_thread;
-use tokio::net;
 
-use tokio_io::AsyncRead;
+use tokio_io::{AsyncRead,AsyncWrite};
 
 use secp256k1::key::{SecretKey,PublicKey};
 use secp256k1::Secp256k1;
@@ -30,23 +32,38 @@ use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::io;
 use std::rc::Rc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 struct MiningClient {
 	stream: mpsc::Sender<WorkMessage>,
 	client_id: u64,
+	slot: ClientSlot,
 	use_header_variants: bool,
+	keepalive: bool,
 	handshake_complete: bool,
 }
 
+struct MiningJob {
+	work: WorkInfo,
+	received: Instant,
+	shares: SubmittedShares,
+}
+
 pub struct MiningServer {
 	secp_ctx: Secp256k1,
 	auth_key: SecretKey,
 
 	clients: Vec<Rc<RefCell<MiningClient>>>,
 	client_id_max: u64,
-	// TODO: Limit size of jobs by evicting old ones
-	jobs: BTreeMap<u64, WorkInfo>,
+	/// Shared with the other workers' servers and the main thread, which only need the total
+	clients_connected: Arc<AtomicUsize>,
+	/// Jobs on the current best block, which are dropped once a job on a new block comes in or once
+	/// they fall outside of job_retention
+	jobs: BTreeMap<u64, MiningJob>,
+	job_retention: JobRetention,
+	duplicate_shares: Arc<DuplicateShareCounts>,
 }
 
 fn work_to_coinbase_tx(template: &BlockTemplate, client_id: u64) -> Transaction {
@@ -109,14 +126,17 @@ macro_rules! sign_message {
 }
 
 impl MiningServer {
-	pub fn new(job_providers: mpsc::Receiver<WorkInfo>, auth_key: SecretKey) -> Rc<RefCell<Self>> {
+	pub fn new(job_providers: sync_mpsc::Receiver<WorkInfo>, auth_key: SecretKey, worker_id: u16, clients_connected: Arc<AtomicUsize>, job_retention: JobRetention, duplicate_shares: Arc<DuplicateShareCounts>) -> Rc<RefCell<Self>> {
 		let us = Rc::new(RefCell::new(Self {
 			secp_ctx: Secp256k1::new(),
 			auth_key: auth_key,
 
 			clients: Vec::new(),
-			client_id_max: 0,
+			client_id_max: (worker_id as u64) << 48,
+			clients_connected: clients_connected,
 			jobs: BTreeMap::new(),
+			job_retention: job_retention,
+			duplicate_shares: duplicate_shares,
 		}));
 
 		let us_cp = us.clone();
@@ -160,16 +180,24 @@ impl MiningServer {
 				}
 			});
 
-			self_ref.jobs.insert(job.template.template_id, job);
+			self_ref.jobs.insert(job.template.template_id, MiningJob {
+				work: job,
+				received: Instant::now(),
+				shares: SubmittedShares::new(),
+			});
+			self_ref.evict_jobs(Instant::now());
 			future::result(Ok(()))
 		}));
 
 		us
 	}
 
-	pub fn new_connection(rc: Rc<RefCell<Self>>, stream: net::TcpStream) {
-		stream.set_nodelay(true).unwrap();
+	/// Drops jobs which fall outside of job_retention or are built on a previous block
+	fn evict_jobs(&mut self, now: Instant) {
+		self.job_retention.evict(&mut self.jobs, now, |job| (job.work.template.header_prevblock, job.received));
+	}
 
+	pub fn new_connection<S: AsyncRead + AsyncWrite + 'static>(rc: Rc<RefCell<Self>>, stream: S, slot: ClientSlot) {
 		let (tx, rx) = stream.framed(WorkMsgFramer::new()).split();
 
 		let client_ref = {
@@ -184,7 +212,9 @@ impl MiningServer {
 			let client = Rc::new(RefCell::new(MiningClient {
 				stream: send_sink,
 				client_id: us.client_id_max,
+				slot: slot,
 				use_header_variants: false,
+				keepalive: false,
 				handshake_complete: false,
 			}));
 			println!("Got new client connection (id {})", us.client_id_max);
@@ -192,6 +222,7 @@ impl MiningServer {
 
 			let client_ref = client.clone();
 			us.clients.push(client);
+			us.clients_connected.fetch_add(1, Ordering::AcqRel);
 			client_ref
 		};
 
@@ -203,6 +234,10 @@ impl MiningServer {
 
 		current_thread::spawn(rx.for_each(move |msg| -> future::FutureResult<(), io::Error> {
 			let mut client = client_ref.borrow_mut();
+			if !client.slot.message_received() {
+				println!("Client {} exceeded the message rate limit", client.client_id);
+				return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+			}
 			macro_rules! send_response {
 				($msg: expr) => {
 					match client.stream.start_send($msg) {
@@ -221,11 +256,12 @@ impl MiningServer {
 						return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 					}
 					client.use_header_variants = (flags & 0b11) == 0b11;
+					client.keepalive = (flags & WORK_FLAG_KEEPALIVE) != 0;
 					client.handshake_complete = true;
 					let us = rc.borrow();
 					send_response!(WorkMessage::ProtocolVersion {
 						selected_version: 1,
-						flags: if (flags & 0b11) == 0b11 { 0b11 } else { 0b01 },
+						flags: (if (flags & 0b11) == 0b11 { 0b11 } else { 0b01 }) | (flags & WORK_FLAG_KEEPALIVE),
 						auth_key: PublicKey::from_secret_key(&us.secp_ctx, &us.auth_key).unwrap(),
 					});
 					if !client.use_header_variants {
@@ -243,8 +279,8 @@ impl MiningServer {
 					match rc.borrow().jobs.iter().last() { //TODO: This is ineffecient, map should have a last()
 						Some(job) => {
 							send_response!(WorkMessage::BlockTemplate {
-								signature: sign_message!(job.1.template, 3, us),
-								template: (*job.1.template).clone(),
+								signature: sign_message!(job.1.work.template, 3, us),
+								template: (*job.1.work.template).clone(),
 							});
 						}, None => {}
 					}
@@ -258,8 +294,10 @@ impl MiningServer {
 					return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 				},
 				WorkMessage::WinningNonce { nonces } => {
-					match rc.borrow().jobs.get(&nonces.template_id) {
-						Some(job) => {
+					rc.borrow_mut().evict_jobs(Instant::now());
+					let us = rc.borrow();
+					match us.jobs.get(&nonces.template_id) {
+						Some(&MiningJob { work: ref job, ref shares, .. }) => {
 							let block_hash = BlockHeader {
 								version: nonces.header_version,
 								prev_blockhash: Sha256dHash::from(&job.template.header_prevblock[..]),
@@ -270,7 +308,13 @@ impl MiningServer {
 							}.bitcoin_hash();
 
 							if utils::does_hash_meet_target(&block_hash[..], &job.template.target[..]) {
-								match job.solutions.unbounded_send(Rc::new((nonces, block_hash))) {
+								if !shares.check_new(&block_hash[..]) {
+									let worker = String::from_utf8_lossy(&nonces.user_tag).into_owned();
+									println!("Got duplicate share from {} (worker {})", client.client_id, worker);
+									us.duplicate_shares.record(&worker);
+									return future::result(Ok(()));
+								}
+								match job.solutions.unbounded_send(Arc::new((nonces, block_hash))) {
 									Ok(_) => {},
 									Err(_) => { panic!(); },
 								};
@@ -301,8 +345,10 @@ impl MiningServer {
 					return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 				},
 				WorkMessage::WinningNonceHeader { template_id, template_variant, header_version, header_time, header_nonce, user_tag } => {
-					match rc.borrow().jobs.get(&template_id) {
-						Some(job) => {
+					rc.borrow_mut().evict_jobs(Instant::now());
+					let us = rc.borrow();
+					match us.jobs.get(&template_id) {
+						Some(&MiningJob { work: ref job, ref shares, .. }) => {
 							let block_hash = BlockHeader {
 								version: header_version,
 								prev_blockhash: Sha256dHash::from(&job.template.header_prevblock[..]),
@@ -313,7 +359,13 @@ impl MiningServer {
 							}.bitcoin_hash();
 
 							if utils::does_hash_meet_target(&block_hash[..], &job.template.target[..]) {
-								match job.solutions.unbounded_send(Rc::new((WinningNonce {
+								if !shares.check_new(&block_hash[..]) {
+									let worker = String::from_utf8_lossy(&user_tag).into_owned();
+									println!("Got duplicate share from {} (worker {})", client.client_id, worker);
+									us.duplicate_shares.record(&worker);
+									return future::result(Ok(()));
+								}
+								match job.solutions.unbounded_send(Arc::new((WinningNonce {
 									template_id,
 									header_version,
 									header_time,
@@ -333,13 +385,29 @@ impl MiningServer {
 						}
 					}
 				},
+				WorkMessage::Ping { nonce } => {
+					if !client.keepalive {
+						return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+					}
+					send_response!(WorkMessage::Pong { nonce });
+				},
+				WorkMessage::Pong { .. } => {
+					println!("Received Pong?");
+					return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+				},
 			}
 			future::result(Ok(()))
-		}).then(move |_| {
+		}).then(move |res| {
+			// We close connections with InvalidData when the client misbehaves
+			match res {
+				Err(ref e) if e.kind() == io::ErrorKind::InvalidData => client_ref_close.borrow().slot.protocol_violation(),
+				_ => {},
+			}
 			let mut us = rc_close.borrow_mut();
 			us.clients.retain(|client| {
 				!Rc::ptr_eq(&client_ref_close, client)
 			});
+			us.clients_connected.fetch_sub(1, Ordering::AcqRel);
 			println!("Client {} disconnected, now have {} clients!", client_ref_close.borrow().client_id, us.clients.len());
 			future::result(Ok(()))
 		}));
@@ -6,7 +6,8 @@ use bitcoin::network;
 use bytes;
 use bytes::BufMut;
 
-use futures::unsync::mpsc;
+use futures::future;
+use futures::sync::{mpsc,oneshot};
 
 use tokio_io::codec;
 
@@ -17,7 +18,7 @@ use secp256k1::Signature;
 use std::error::Error;
 use std::fmt;
 use std::io;
-use std::rc::Rc;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct BlockTemplate {
@@ -185,12 +186,21 @@ impl BlockTemplateHeader {
 	}
 }
 
+/// A template's transactions, which the job provider sends some time after the template itself.
+/// Resolves to an error if they never arrive.
+pub type JobTransactions = future::Shared<oneshot::Receiver<Arc<Vec<Transaction>>>>;
+
 #[derive(Clone)]
 pub struct WorkInfo {
-	pub template: Rc<BlockTemplate>,
-	pub solutions: mpsc::UnboundedSender<Rc<(WinningNonce, Sha256dHash)>>,
+	pub template: Arc<BlockTemplate>,
+	pub solutions: mpsc::UnboundedSender<Arc<(WinningNonce, Sha256dHash)>>,
+	pub transactions: JobTransactions,
 }
 
+/// Work protocol flag indicating support for Ping/Pong keepalives. The two low bits are used by
+/// mining clients to select how they are paid out.
+pub const WORK_FLAG_KEEPALIVE: u16 = 1 << 2;
+
 pub enum WorkMessage {
 	ProtocolSupport {
 		max_version: u16,
@@ -232,6 +242,12 @@ pub enum WorkMessage {
 		header_nonce: u32,
 		user_tag: Vec<u8>,
 	},
+	Ping {
+		nonce: u64,
+	},
+	Pong {
+		nonce: u64,
+	},
 }
 
 pub struct WorkMsgFramer {
@@ -324,6 +340,16 @@ impl codec::Encoder for WorkMsgFramer {
 				res.put_u8(user_tag.len() as u8);
 				res.put_slice(&user_tag[..]);
 			},
+			WorkMessage::Ping { nonce } => {
+				res.reserve(1 + 8);
+				res.put_u8(10);
+				res.put_u64::<bytes::LittleEndian>(nonce);
+			},
+			WorkMessage::Pong { nonce } => {
+				res.reserve(1 + 8);
+				res.put_u8(11);
+				res.put_u64::<bytes::LittleEndian>(nonce);
+			},
 		}
 		Ok(())
 	}
@@ -627,6 +653,20 @@ impl codec::Decoder for WorkMsgFramer {
 				advance_bytes!();
 				Ok(Some(msg))
 			},
+			10 => {
+				let msg = WorkMessage::Ping {
+					nonce: slice_to_le64(get_slice!(8)),
+				};
+				advance_bytes!();
+				Ok(Some(msg))
+			},
+			11 => {
+				let msg = WorkMessage::Pong {
+					nonce: slice_to_le64(get_slice!(8)),
+				};
+				advance_bytes!();
+				Ok(Some(msg))
+			},
 			_ => {
 				return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError))
 			}
@@ -681,6 +721,10 @@ impl PoolDifficulty {
 
 #[derive(Clone)]
 pub struct PoolShare {
+	/// Opaque identifier chosen by the client, echoed back in the pool's ShareResult. Only sent
+	/// (as a ShareWithId message) when POOL_FLAG_SHARE_ACKS was negotiated.
+	pub share_id: Option<u64>,
+
 	pub header_version: u32,
 	pub header_prevblock: [u8; 32],
 	pub header_time: u32,
@@ -693,6 +737,53 @@ pub struct PoolShare {
 	pub user_tag: Vec<u8>,
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum ShareStatus {
+	Accepted, // 0
+	Rejected, // 1
+	/// The share was valid but built on a block which is no longer the tip
+	Stale, // 2
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ShareRejectReason {
+	None, // 0
+	LowDifficulty, // 1
+	BadCoinbase, // 2
+	BadPayout, // 3
+	UnknownUser, // 4
+	Duplicate, // 5
+	/// Any reason code we do not know about
+	Other, // 0xff
+}
+
+#[derive(Clone)]
+pub struct PoolShareResult {
+	pub share_id: u64,
+	pub status: ShareStatus,
+	pub reason: ShareRejectReason,
+}
+impl PoolShareResult {
+	pub fn encode_unsigned(&self, res: &mut bytes::BytesMut) {
+		res.reserve(8 + 1 + 1);
+		res.put_u64::<bytes::LittleEndian>(self.share_id);
+		res.put_u8(match self.status {
+			ShareStatus::Accepted => 0,
+			ShareStatus::Rejected => 1,
+			ShareStatus::Stale => 2,
+		});
+		res.put_u8(match self.reason {
+			ShareRejectReason::None => 0,
+			ShareRejectReason::LowDifficulty => 1,
+			ShareRejectReason::BadCoinbase => 2,
+			ShareRejectReason::BadPayout => 3,
+			ShareRejectReason::UnknownUser => 4,
+			ShareRejectReason::Duplicate => 5,
+			ShareRejectReason::Other => 0xff,
+		});
+	}
+}
+
 #[derive(Clone)]
 pub enum WeakBlockAction {
 	/// Skips the next n transactions from the original sketch
@@ -768,6 +859,15 @@ impl WeakBlock {
 	}
 }
 
+/// Pool protocol capability flags. A client sets every flag it supports in ProtocolSupport and the
+/// server replies in ProtocolVersion with the subset it also supports, which both sides then use.
+pub const POOL_FLAG_WEAK_BLOCKS: u16 = 1 << 0;
+pub const POOL_FLAG_SHARE_ACKS: u16 = 1 << 1;
+// 1 << 2 is reserved for authenticating PayoutInfoRequest's user_auth, which is not yet defined
+pub const POOL_FLAG_REDIRECT: u16 = 1 << 3;
+/// Either side may send Ping, which must be answered with a Pong carrying the same nonce
+pub const POOL_FLAG_KEEPALIVE: u16 = 1 << 4;
+
 pub enum PoolMessage {
 	ProtocolSupport {
 		max_version: u16,
@@ -802,6 +902,16 @@ pub enum PoolMessage {
 		signature: Signature,
 		new_host_port: String,
 	},
+	ShareResult {
+		signature: Signature,
+		result: PoolShareResult,
+	},
+	Ping {
+		nonce: u64,
+	},
+	Pong {
+		nonce: u64,
+	},
 /*TODO:
 	BitcoindAddNode {
 		signature: Signature,
@@ -864,8 +974,14 @@ impl codec::Encoder for PoolMsgFramer {
 			},
 			PoolMessage::Share { ref share } => {
 				let tx_enc = network::serialize::serialize(&share.coinbase_tx).unwrap();
-				res.reserve(1 + 4*4 + 32 + 1 + share.merkle_rhss.len()*32 + 4 + tx_enc.len() + 1 + share.user_tag.len());
-				res.put_u8(13);
+				res.reserve(1 + 8 + 4*4 + 32 + 1 + share.merkle_rhss.len()*32 + 4 + tx_enc.len() + 1 + share.user_tag.len());
+				match share.share_id {
+					Some(share_id) => {
+						res.put_u8(20);
+						res.put_u64::<bytes::LittleEndian>(share_id);
+					},
+					None => res.put_u8(13),
+				}
 				res.put_u32::<bytes::LittleEndian>(share.header_version);
 				res.put_slice(&share.header_prevblock);
 				res.put_u32::<bytes::LittleEndian>(share.header_time);
@@ -895,7 +1011,23 @@ impl codec::Encoder for PoolMsgFramer {
 				res.put_slice(&signature.serialize_compact(&self.secp_ctx));
 				res.put_u8(new_host_port.len() as u8);
 				res.put_slice(new_host_port.as_bytes());
-			}
+			},
+			PoolMessage::ShareResult { ref signature, ref result } => {
+				res.reserve(1 + 64);
+				res.put_u8(17);
+				res.put_slice(&signature.serialize_compact(&self.secp_ctx));
+				result.encode_unsigned(res);
+			},
+			PoolMessage::Ping { nonce } => {
+				res.reserve(1 + 8);
+				res.put_u8(18);
+				res.put_u64::<bytes::LittleEndian>(nonce);
+			},
+			PoolMessage::Pong { nonce } => {
+				res.reserve(1 + 8);
+				res.put_u8(19);
+				res.put_u64::<bytes::LittleEndian>(nonce);
+			},
 		}
 		Ok(())
 	}
@@ -1023,7 +1155,8 @@ impl codec::Decoder for PoolMsgFramer {
 				advance_bytes!();
 				Ok(Some(msg))
 			},
-			13 => {
+			13|20 => {
+				let share_id = if bytes[0] == 20 { Some(slice_to_le64(get_slice!(8))) } else { None };
 				let header_version = slice_to_le32(get_slice!(4));
 				let mut header_prevblock = [0; 32];
 				header_prevblock.copy_from_slice(get_slice!(32));
@@ -1056,6 +1189,7 @@ impl codec::Decoder for PoolMsgFramer {
 
 				let msg = PoolMessage::Share {
 					share: PoolShare {
+						share_id,
 						header_version,
 						header_prevblock,
 						header_time,
@@ -1093,10 +1227,196 @@ impl codec::Decoder for PoolMsgFramer {
 				};
 				advance_bytes!();
 				Ok(Some(msg))
-			}
+			},
+			17 => {
+				let signature = match Signature::from_compact(&self.secp_ctx, get_slice!(64)) {
+					Ok(sig) => sig,
+					Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError))
+				};
+				let share_id = slice_to_le64(get_slice!(8));
+				let status = match get_slice!(1)[0] {
+					0 => ShareStatus::Accepted,
+					1 => ShareStatus::Rejected,
+					2 => ShareStatus::Stale,
+					_ => return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError))
+				};
+				let reason = match get_slice!(1)[0] {
+					0 => ShareRejectReason::None,
+					1 => ShareRejectReason::LowDifficulty,
+					2 => ShareRejectReason::BadCoinbase,
+					3 => ShareRejectReason::BadPayout,
+					4 => ShareRejectReason::UnknownUser,
+					5 => ShareRejectReason::Duplicate,
+					_ => ShareRejectReason::Other,
+				};
+				let msg = PoolMessage::ShareResult {
+					signature,
+					result: PoolShareResult {
+						share_id,
+						status,
+						reason,
+					},
+				};
+				advance_bytes!();
+				Ok(Some(msg))
+			},
+			18 => {
+				let msg = PoolMessage::Ping {
+					nonce: slice_to_le64(get_slice!(8)),
+				};
+				advance_bytes!();
+				Ok(Some(msg))
+			},
+			19 => {
+				let msg = PoolMessage::Pong {
+					nonce: slice_to_le64(get_slice!(8)),
+				};
+				advance_bytes!();
+				Ok(Some(msg))
+			},
 			_ => {
 				return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError))
 			}
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use msg_framing::*;
+
+	use bitcoin::blockdata::transaction::{TxIn,TxOut,Transaction};
+	use bitcoin::blockdata::script::Script;
+
+	use bytes;
+
+	use secp256k1;
+	use secp256k1::key::SecretKey;
+	use secp256k1::Secp256k1;
+
+	use tokio_io::codec::{Decoder,Encoder};
+
+	/// Encodes msg, checks that it decodes from exactly those bytes (and not from any fewer), and
+	/// returns the encoding and the decoded message
+	fn round_trip(msg: PoolMessage) -> (Vec<u8>, PoolMessage) {
+		let mut framer = PoolMsgFramer::new();
+		let mut encoded = bytes::BytesMut::new();
+		framer.encode(msg, &mut encoded).unwrap();
+		let encoded = encoded.to_vec();
+
+		let mut partial = bytes::BytesMut::from(&encoded[..encoded.len() - 1]);
+		assert!(framer.decode(&mut partial).unwrap().is_none());
+
+		let mut buf = bytes::BytesMut::from(&encoded[..]);
+		let decoded = framer.decode(&mut buf).unwrap().unwrap();
+		assert!(buf.is_empty());
+
+		let mut reencoded = bytes::BytesMut::new();
+		let decoded_copy = match decoded {
+			PoolMessage::Share { ref share } => PoolMessage::Share { share: share.clone() },
+			PoolMessage::ShareResult { ref signature, ref result } => PoolMessage::ShareResult { signature: signature.clone(), result: result.clone() },
+			_ => panic!("Decoded a different message type"),
+		};
+		framer.encode(decoded_copy, &mut reencoded).unwrap();
+		assert_eq!(reencoded.to_vec(), encoded);
+		(encoded, decoded)
+	}
+
+	fn test_share(share_id: Option<u64>) -> PoolShare {
+		PoolShare {
+			share_id,
+			header_version: 0x20000000,
+			header_prevblock: [0x11; 32],
+			header_time: 1_500_000_000,
+			header_nbits: 0x1d00ffff,
+			header_nonce: 42,
+			merkle_rhss: vec![[0x22; 32], [0x33; 32]],
+			coinbase_tx: Transaction {
+				version: 1,
+				input: vec!(TxIn {
+					prev_hash: Default::default(),
+					prev_index: 0xffffffff,
+					script_sig: Script::from(vec![0; 16]),
+					sequence: 0xffffffff,
+				}),
+				output: vec!(TxOut {
+					value: 5000,
+					script_pubkey: Script::new(),
+				}),
+				witness: vec!(),
+				lock_time: 0,
+			},
+			user_tag: b"worker".to_vec(),
+		}
+	}
+
+	#[test]
+	fn test_share_encoding() {
+		// Without share acks the share is sent as it always was, with no id
+		let (encoded, decoded) = round_trip(PoolMessage::Share { share: test_share(None) });
+		assert_eq!(encoded[0], 13);
+		match decoded {
+			PoolMessage::Share { share } => {
+				assert_eq!(share.share_id, None);
+				assert_eq!(share.header_nonce, 42);
+				assert_eq!(share.merkle_rhss.len(), 2);
+				assert_eq!(share.user_tag, b"worker".to_vec());
+			},
+			_ => panic!(),
+		}
+
+		let (encoded_with_id, decoded) = round_trip(PoolMessage::Share { share: test_share(Some(0x0102030405060708)) });
+		assert_eq!(encoded_with_id[0], 20);
+		assert_eq!(&encoded_with_id[1..9], &[8, 7, 6, 5, 4, 3, 2, 1]);
+		assert_eq!(&encoded_with_id[9..], &encoded[1..]);
+		match decoded {
+			PoolMessage::Share { share } => assert_eq!(share.share_id, Some(0x0102030405060708)),
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn test_share_result_encoding() {
+		let secp_ctx = Secp256k1::new();
+		let key = SecretKey::from_slice(&secp_ctx, &[0x42; 32]).unwrap();
+		let signature = secp_ctx.sign(&secp256k1::Message::from_slice(&[0x01; 32]).unwrap(), &key).unwrap();
+
+		for &(status, reason, status_byte, reason_byte) in [
+			(ShareStatus::Accepted, ShareRejectReason::None, 0, 0),
+			(ShareStatus::Rejected, ShareRejectReason::BadPayout, 1, 3),
+			(ShareStatus::Stale, ShareRejectReason::Duplicate, 2, 5),
+		].iter() {
+			let (encoded, decoded) = round_trip(PoolMessage::ShareResult {
+				signature: signature.clone(),
+				result: PoolShareResult { share_id: 7, status, reason },
+			});
+			assert_eq!(encoded.len(), 1 + 64 + 8 + 1 + 1);
+			assert_eq!(encoded[0], 17);
+			assert_eq!(&encoded[65..73], &[7, 0, 0, 0, 0, 0, 0, 0]);
+			assert_eq!(encoded[73], status_byte);
+			assert_eq!(encoded[74], reason_byte);
+			match decoded {
+				PoolMessage::ShareResult { signature: decoded_signature, result } => {
+					assert!(decoded_signature == signature);
+					assert_eq!(result.share_id, 7);
+					assert!(result.status == status);
+					assert_eq!(result.reason, reason);
+				},
+				_ => panic!(),
+			}
+		}
+
+		// Reason codes we don't know map to Other, but unknown statuses are invalid
+		let mut encoded = round_trip(PoolMessage::ShareResult {
+			signature: signature.clone(),
+			result: PoolShareResult { share_id: 7, status: ShareStatus::Rejected, reason: ShareRejectReason::None },
+		}).0;
+		encoded[74] = 0x42;
+		match PoolMsgFramer::new().decode(&mut bytes::BytesMut::from(&encoded[..])).unwrap().unwrap() {
+			PoolMessage::ShareResult { result, .. } => assert_eq!(result.reason, ShareRejectReason::Other),
+			_ => panic!(),
+		}
+		encoded[73] = 3;
+		assert!(PoolMsgFramer::new().decode(&mut bytes::BytesMut::from(&encoded[..])).is_err());
+	}
+}
@@ -52,6 +52,9 @@ pub fn slice_to_le64(v: &[u8]) -> u64 {
 	((v[0] as u64) << 8*0)
 }
 
+/// The pool protocol features this pool knows how to use
+const SUPPORTED_FLAGS: u16 = POOL_FLAG_SHARE_ACKS | POOL_FLAG_KEEPALIVE;
+
 const SHARE_TARGET: [u8; 32] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 0, 0, 0]; // Diff 65536
 fn main() {
 	println!("USAGE: sample-pool --listen_bind=IP:port --auth_key=base58privkey --payout_address=addr [--server_id=up_to_36_byte_string_for_coinbase]");
@@ -181,6 +184,7 @@ fn main() {
 
 					let mut received_protocol_support = false;
 					let mut client_authed = false;
+					let mut negotiated_flags = 0;
 					current_thread::spawn(rx.for_each(move |msg| {
 						macro_rules! send_response {
 							($msg: expr) => {
@@ -196,12 +200,13 @@ fn main() {
 								if min_version > 1 || max_version < 1 {
 									return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 								}
-								if flags != 0 {
-									println!("Client requested unknown flags {}", flags);
+								if (flags & !SUPPORTED_FLAGS) != 0 {
+									println!("Client offered flags we do not support ({}), ignoring them", flags & !SUPPORTED_FLAGS);
 								}
+								negotiated_flags = flags & SUPPORTED_FLAGS;
 								send_response!(PoolMessage::ProtocolVersion {
 									selected_version: 1,
-									flags: 0,
+									flags: negotiated_flags,
 									auth_key: PublicKey::from_secret_key(&secp_ctx, &auth_key.unwrap()).unwrap(),
 								});
 								received_protocol_support = true;
@@ -210,10 +215,14 @@ fn main() {
 								println!("Got ProtocolVersion?");
 								return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 							},
-							PoolMessage::PayoutInfoRequest { user_id, .. } => {
+							PoolMessage::PayoutInfoRequest { user_id, user_auth } => {
 								if !received_protocol_support {
 									return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 								}
+								if !user_auth.is_empty() {
+									println!("Got PayoutInfoRequest with user_auth, which cannot be negotiated yet");
+									return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+								}
 								if client_authed {
 									return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 								}
@@ -263,10 +272,35 @@ fn main() {
 								if !received_protocol_support || !client_authed {
 									return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 								}
+								// Shares carry an id exactly when we negotiated acking them
+								if share.share_id.is_some() != ((negotiated_flags & POOL_FLAG_SHARE_ACKS) != 0) {
+									println!("Client sent a share id without negotiating share acks, or vice versa");
+									return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+								}
+
+								macro_rules! share_result {
+									($status: expr, $reason: expr) => {
+										{
+											if (negotiated_flags & POOL_FLAG_SHARE_ACKS) == 0 {
+												return future::result(Ok(()));
+											}
+											let result = PoolShareResult {
+												share_id: share.share_id.unwrap(),
+												status: $status,
+												reason: $reason,
+											};
+											send_response!(PoolMessage::ShareResult {
+												signature: sign_message!(result, 9),
+												result,
+											});
+											return future::result(Ok(()));
+										}
+									}
+								}
 
 								if share.coinbase_tx.input.len() != 1 || share.coinbase_tx.output.len() < 1 {
 									println!("Client sent share with a coinbase_tx which had an input count other than 1 or no payout");
-									return future::result(Ok(()));
+									share_result!(ShareStatus::Rejected, ShareRejectReason::BadCoinbase);
 								}
 
 								let coinbase = &share.coinbase_tx.input[0].script_sig[..];
@@ -274,7 +308,7 @@ fn main() {
 									Some(ref server_id) => {
 										if coinbase.len() < server_id.len() + 8 || !coinbase.ends_with(&server_id.as_bytes()[..]) {
 											println!("Client sent share which failed to include the required coinbase postfix");
-											return future::result(Ok(()));
+											share_result!(ShareStatus::Rejected, ShareRejectReason::BadCoinbase);
 										}
 										slice_to_le64(&coinbase[coinbase.len() - server_id.len() - 8..coinbase.len() - server_id.len()])
 
@@ -282,17 +316,16 @@ fn main() {
 									None => {
 										if coinbase.len() < 8 {
 											println!("Client sent share which failed to include the required coinbase postfix");
-											return future::result(Ok(()));
+											share_result!(ShareStatus::Rejected, ShareRejectReason::BadCoinbase);
 										}
 										slice_to_le64(&coinbase[coinbase.len() - 8..coinbase.len()])
 									},
 								};
-								let clients_ref = clients.borrow();
-								let client_payout = match clients_ref.get(&share_client_id) {
-									Some(payout_addr) => payout_addr,
+								let client_payout = match clients.borrow().get(&share_client_id) {
+									Some(payout_addr) => payout_addr.clone(),
 									None => {
 										println!("Client sent share with a coinbase_tx which did not pay to a known auth'ed client");
-										return future::result(Ok(()));
+										share_result!(ShareStatus::Rejected, ShareRejectReason::UnknownUser);
 									}
 								};
 
@@ -300,11 +333,11 @@ fn main() {
 									if idx == 0 {
 										if out.script_pubkey != payout_addr_clone {
 											println!("Got share which paid out to unknown location");
-											return future::result(Ok(()));
+											share_result!(ShareStatus::Rejected, ShareRejectReason::BadPayout);
 										}
 									} else if out.value != 0 {
 										println!("Got share which paid out excess to unkown location");
-										return future::result(Ok(()));
+										share_result!(ShareStatus::Rejected, ShareRejectReason::BadPayout);
 									}
 								}
 
@@ -332,11 +365,17 @@ fn main() {
 
 								if utils::does_hash_meet_target(&block_hash[..], &SHARE_TARGET) {
 									println!("Got valid share from {} for payout to script: {}", String::from_utf8_lossy(&share.user_tag), client_payout.to_string());
+									share_result!(ShareStatus::Accepted, ShareRejectReason::None);
 								} else {
 									println!("Got work that missed target (hashed to {}, which is greater than {})", utils::bytes_to_hex(&block_hash[..]), utils::bytes_to_hex(&SHARE_TARGET[..]));
+									share_result!(ShareStatus::Rejected, ShareRejectReason::LowDifficulty);
 								}
 							},
 							PoolMessage::WeakBlock { .. } => {
+								if (negotiated_flags & POOL_FLAG_WEAK_BLOCKS) == 0 {
+									println!("Got WeakBlock without negotiating weak blocks");
+									return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+								}
 								println!("Got WeakBlock with infinite difficulty?");
 								return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 							},
@@ -345,9 +384,27 @@ fn main() {
 								return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 							},
 							PoolMessage::NewPoolServer { .. } => {
+								if (negotiated_flags & POOL_FLAG_REDIRECT) == 0 {
+									println!("Got NewPoolServer without negotiating redirect support");
+									return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+								}
 								println!("Got NewPoolServer?");
 								return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 							},
+							PoolMessage::ShareResult { .. } => {
+								println!("Got ShareResult?");
+								return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+							},
+							PoolMessage::Ping { nonce } => {
+								if (negotiated_flags & POOL_FLAG_KEEPALIVE) == 0 {
+									return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+								}
+								send_response!(PoolMessage::Pong { nonce });
+							},
+							PoolMessage::Pong { .. } => {
+								println!("Got Pong for a Ping we never sent?");
+								return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+							},
 						}
 						future::result(Ok(()))
 					}).then(|_| {
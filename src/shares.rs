@@ -0,0 +1,134 @@
+// Which jobs shares are accepted on, and duplicate share detection. Servers drop old jobs per
+// JobRetention, each job keeps a SubmittedShares, dropped along with the job, and every worker's
+// servers record the duplicates they reject in one shared DuplicateShareCounts.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap,HashMap,HashSet};
+use std::sync::{Arc,Mutex};
+use std::time::{Duration,Instant};
+
+/// How long we keep old jobs around to accept shares on them. The latest job is always kept.
+#[derive(Clone, Copy)]
+pub struct JobRetention {
+	/// Maximum number of jobs, including the latest one
+	pub max_jobs: usize,
+	/// Jobs received longer ago than this are dropped
+	pub max_age: Duration,
+}
+
+impl Default for JobRetention {
+	fn default() -> Self {
+		Self {
+			max_jobs: 16,
+			max_age: Duration::from_secs(300),
+		}
+	}
+}
+
+impl JobRetention {
+	/// Drops jobs, keyed by their (increasing) job id, which are too old, too many or built on a
+	/// previous block. job_info gives a job's prevblock and when we received it.
+	pub fn evict<Job, F: Fn(&Job) -> ([u8; 32], Instant)>(&self, jobs: &mut BTreeMap<u64, Job>, now: Instant, job_info: F) {
+		let (latest_id, latest_prevblock) = match jobs.iter().next_back() {
+			Some((id, job)) => (*id, job_info(job).0),
+			None => return,
+		};
+		let max_age = self.max_age;
+		jobs.retain(|id, job| {
+			let (prevblock, received) = job_info(job);
+			*id == latest_id || (prevblock == latest_prevblock && now.duration_since(received) < max_age)
+		});
+		while jobs.len() > self.max_jobs {
+			let oldest_id = *jobs.keys().next().unwrap();
+			jobs.remove(&oldest_id);
+		}
+	}
+}
+
+/// The most shares we remember per job. Past this new shares are still accepted, we just can't
+/// spot repeats of them any more.
+const MAX_SHARES_PER_JOB: usize = 1 << 16;
+
+/// The block hashes of the shares submitted on one job. A block hash commits to the coinbase
+/// (and so the client's extranonces), ntime, nonce and version, so any repeat of those hashes the
+/// same.
+pub struct SubmittedShares {
+	hashes: RefCell<HashSet<[u8; 32]>>,
+}
+
+impl SubmittedShares {
+	pub fn new() -> Self {
+		Self {
+			hashes: RefCell::new(HashSet::new()),
+		}
+	}
+
+	/// Records a share's block hash, returning false if it was already submitted on this job
+	pub fn check_new(&self, block_hash: &[u8]) -> bool {
+		let mut hash = [0; 32];
+		hash.copy_from_slice(block_hash);
+		let mut hashes = self.hashes.borrow_mut();
+		if hashes.contains(&hash) { return false; }
+		if hashes.len() < MAX_SHARES_PER_JOB {
+			hashes.insert(hash);
+		}
+		true
+	}
+}
+
+/// Number of duplicate shares rejected, by the worker name the client submitted them under
+pub struct DuplicateShareCounts {
+	counts: Mutex<HashMap<String, u64>>,
+}
+
+impl DuplicateShareCounts {
+	pub fn new() -> Arc<Self> {
+		Arc::new(Self {
+			counts: Mutex::new(HashMap::new()),
+		})
+	}
+
+	pub fn record(&self, worker: &str) {
+		*self.counts.lock().unwrap().entry(worker.to_string()).or_insert(0) += 1;
+	}
+
+	/// Gets the counts so far, sorted by worker name
+	pub fn get_counts(&self) -> Vec<(String, u64)> {
+		let mut counts: Vec<_> = self.counts.lock().unwrap().iter().map(|(worker, count)| (worker.clone(), *count)).collect();
+		counts.sort();
+		counts
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use shares::*;
+
+	#[test]
+	fn test_submitted_shares() {
+		let shares = SubmittedShares::new();
+		assert!(shares.check_new(&[1; 32]));
+		assert!(shares.check_new(&[2; 32]));
+		assert!(!shares.check_new(&[1; 32]));
+		assert!(!shares.check_new(&[2; 32]));
+
+		// Once full, new shares are accepted without being remembered
+		for i in 2..MAX_SHARES_PER_JOB as u32 {
+			let mut hash = [0; 32];
+			hash[0..4].copy_from_slice(&[i as u8, (i >> 8) as u8, (i >> 16) as u8, 0xff]);
+			assert!(shares.check_new(&hash));
+		}
+		assert!(shares.check_new(&[3; 32]));
+		assert!(shares.check_new(&[3; 32]));
+		assert!(!shares.check_new(&[1; 32]));
+	}
+
+	#[test]
+	fn test_duplicate_counts() {
+		let counts = DuplicateShareCounts::new();
+		counts.record("worker2");
+		counts.record("worker1");
+		counts.record("worker2");
+		assert_eq!(counts.get_counts(), vec![("worker1".to_string(), 1), ("worker2".to_string(), 2)]);
+	}
+}
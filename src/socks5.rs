@@ -0,0 +1,176 @@
+// Minimal SOCKS5 (RFC 1928) client supporting unauthenticated CONNECT, always passing hostnames
+// to the proxy so that it does the DNS resolution (which is required for .onion hosts).
+
+use futures::future;
+use futures::Future;
+
+use tokio::net;
+
+use tokio_io::io::{read_exact,write_all};
+
+use utils;
+
+use std::io;
+use std::net::{IpAddr,SocketAddr};
+
+/// Splits a host:port (or [ipv6]:port) string into its host and port
+pub fn split_host_port(host_port: &str) -> Option<(String, u16)> {
+	let colon = match host_port.rfind(':') {
+		Some(idx) => idx,
+		None => return None,
+	};
+	let port = match host_port[colon + 1..].parse() {
+		Ok(port) => port,
+		Err(_) => return None,
+	};
+	let host = &host_port[..colon];
+	let host = if host.starts_with('[') && host.ends_with(']') {
+		&host[1..host.len() - 1]
+	} else { host };
+	if host.is_empty() || host.len() > 255 {
+		return None;
+	}
+	Some((host.to_string(), port))
+}
+
+fn connect_request(host: &str, port: u16) -> Vec<u8> {
+	let mut req = vec![5, 1, 0];
+	match host.parse::<IpAddr>() {
+		Ok(IpAddr::V4(ip)) => {
+			req.push(1);
+			req.extend_from_slice(&ip.octets());
+		},
+		Ok(IpAddr::V6(ip)) => {
+			req.push(4);
+			req.extend_from_slice(&ip.octets());
+		},
+		Err(_) => {
+			req.push(3);
+			req.push(host.len() as u8);
+			req.extend_from_slice(host.as_bytes());
+		},
+	}
+	req.push((port >> 8) as u8);
+	req.push((port & 0xff) as u8);
+	req
+}
+
+macro_rules! socks_err {
+	() => {
+		io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)
+	}
+}
+
+/// Connects to host_port (which must be of the form host:port) via the SOCKS5 proxy at proxy
+pub fn connect(proxy: &SocketAddr, host_port: &str) -> Box<Future<Item=net::TcpStream, Error=io::Error>> {
+	let (host, port) = match split_host_port(host_port) {
+		Some(host_port) => host_port,
+		None => return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, utils::HandleError))),
+	};
+	let request = connect_request(&host, port);
+
+	Box::new(net::TcpStream::connect(proxy).and_then(|stream| {
+		// Offer only the "no authentication" method
+		write_all(stream, [5, 1, 0])
+	}).and_then(|(stream, _)| {
+		read_exact(stream, [0; 2])
+	}).and_then(|(stream, resp)| {
+		if resp[0] != 5 || resp[1] != 0 {
+			return future::Either::A(future::err(socks_err!()));
+		}
+		future::Either::B(write_all(stream, request))
+	}).and_then(|(stream, _)| {
+		read_exact(stream, [0; 4])
+	}).and_then(|(stream, resp)| {
+		if resp[0] != 5 || resp[1] != 0 {
+			println!("SOCKS5 proxy failed to connect (error {})", resp[1]);
+			return future::Either::A(future::err(socks_err!()));
+		}
+		// Skip the bound address the proxy tells us about (its port is read along with it)
+		let addr_len = match resp[3] {
+			1 => 4 + 2,
+			4 => 16 + 2,
+			3 => return future::Either::B(future::Either::A(read_exact(stream, [0; 1]).and_then(|(stream, len)| {
+				read_exact(stream, vec![0; len[0] as usize + 2])
+			}).map(|(stream, _)| stream))),
+			_ => return future::Either::A(future::err(socks_err!())),
+		};
+		future::Either::B(future::Either::B(read_exact(stream, vec![0; addr_len]).map(|(stream, _)| stream)))
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use socks5;
+
+	use futures::Future;
+
+	use tokio::executor::current_thread;
+
+	use tokio_io::io::{read_exact,write_all};
+
+	use std::io::{Read,Write};
+	use std::net::{SocketAddr,TcpListener};
+	use std::thread;
+
+	/// Runs a single-connection SOCKS5 stand-in which checks the request it receives against
+	/// expected_request, answers with reply_code and then echoes four bytes.
+	fn spawn_proxy(expected_request: Vec<u8>, reply_code: u8) -> SocketAddr {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		thread::spawn(move || {
+			let (mut sock, _) = listener.accept().unwrap();
+			let mut greeting = [0; 3];
+			sock.read_exact(&mut greeting).unwrap();
+			assert_eq!(greeting, [5, 1, 0]);
+			sock.write_all(&[5, 0]).unwrap();
+
+			let mut request = vec![0; expected_request.len()];
+			sock.read_exact(&mut request).unwrap();
+			assert_eq!(request, expected_request);
+			sock.write_all(&[5, reply_code, 0, 1, 127, 0, 0, 1, 0x12, 0x34]).unwrap();
+			if reply_code != 0 { return; }
+
+			let mut echo = [0; 4];
+			sock.read_exact(&mut echo).unwrap();
+			sock.write_all(&echo).unwrap();
+		});
+		addr
+	}
+
+	#[test]
+	fn test_split_host_port() {
+		assert_eq!(socks5::split_host_port("pool.example.com:3333"), Some(("pool.example.com".to_string(), 3333)));
+		assert_eq!(socks5::split_host_port("[::1]:8080"), Some(("::1".to_string(), 8080)));
+		assert_eq!(socks5::split_host_port("pool.example.com"), None);
+		assert_eq!(socks5::split_host_port(":3333"), None);
+	}
+
+	#[test]
+	fn test_connect_onion() {
+		let host = "expyuzz4wqqyqhjn.onion";
+		let mut expected = vec![5, 1, 0, 3, host.len() as u8];
+		expected.extend_from_slice(host.as_bytes());
+		expected.extend_from_slice(&[0x0d, 0x05]);
+		let proxy = spawn_proxy(expected, 0);
+
+		let res = current_thread::block_on_all(socks5::connect(&proxy, "expyuzz4wqqyqhjn.onion:3333").and_then(|stream| {
+			write_all(stream, [1, 2, 3, 4])
+		}).and_then(|(stream, _)| {
+			read_exact(stream, [0; 4])
+		}));
+		assert_eq!(res.unwrap().1, [1, 2, 3, 4]);
+	}
+
+	#[test]
+	fn test_connect_ipv4() {
+		let proxy = spawn_proxy(vec![5, 1, 0, 1, 10, 0, 0, 1, 0x0d, 0x05], 0);
+		assert!(current_thread::block_on_all(socks5::connect(&proxy, "10.0.0.1:3333")).is_ok());
+	}
+
+	#[test]
+	fn test_connect_refused() {
+		let proxy = spawn_proxy(vec![5, 1, 0, 1, 10, 0, 0, 1, 0x0d, 0x05], 5);
+		assert!(current_thread::block_on_all(socks5::connect(&proxy, "10.0.0.1:3333")).is_err());
+	}
+}
@@ -1,24 +1,31 @@
 use msg_framing::{BlockTemplate,WorkInfo,WinningNonce};
+use auth::WorkerVerifier;
+use limits::ClientSlot;
+use shares::{DuplicateShareCounts,JobRetention,SubmittedShares};
+use vardiff::{Vardiff,VardiffConfig};
 use utils;
 
 use bitcoin::blockdata::transaction::{TxIn,Transaction};
 use bitcoin::blockdata::script::Script;
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::network::serialize;
 use bitcoin::network::serialize::BitcoinHash;
 use bitcoin::util::hash::Sha256dHash;
 
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 
-use futures::{future,Future,Sink};
+use futures::{future,AsyncSink,Future,Sink};
 use futures::stream::Stream;
-use futures::unsync::mpsc;
+use futures::sync::mpsc as sync_mpsc;
+use futures::unsync::{mpsc,oneshot};
 
-use tokio::net;
 use tokio::executor::current_thread;
 
-use tokio_io::AsyncRead;
-use tokio_io::codec;
+use tokio_io::{AsyncRead,AsyncWrite};
+use tokio_codec::LinesCodec;
+
+use tokio_timer::Timer;
 
 use serde_json;
 
@@ -30,6 +37,9 @@ use std::error::Error;
 use std::fmt;
 use std::io;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::{Duration,Instant};
 
 #[derive(Debug)]
 struct BadMessageError;
@@ -44,24 +54,6 @@ impl Error for BadMessageError {
 	}
 }
 
-fn extend_vec_from_hex(hex: &str, out: &mut Vec<u8>) -> Result<(), BadMessageError> {
-	let mut b = 0;
-	for (idx, c) in hex.as_bytes().iter().enumerate() {
-		b <<= 4;
-		match *c {
-			b'A'...b'F' => b |= c - b'A' + 10,
-			b'a'...b'f' => b |= c - b'a' + 10,
-			b'0'...b'9' => b |= c - b'0',
-			_ => return Err(BadMessageError),
-		}
-		if (idx & 1) == 1 {
-			out.push(b);
-			b = 0;
-		}
-	}
-	Ok(())
-}
-
 fn hex_to_be32(hex: &str) -> Result<u32, BadMessageError> {
 	if hex.len() != 8 { return Err(BadMessageError); }
 	let mut res = 0;
@@ -143,15 +135,127 @@ fn bytes_to_hex_insane_order(bytes: &[u8; 32]) -> String {
 	out
 }
 
+/// Conventional stratum error codes, sent as the first element of a response's error array
+#[derive(Clone, Copy)]
+enum StratumError {
+	Other = 20,
+	JobNotFound = 21,
+	DuplicateShare = 22,
+	LowDifficulty = 23,
+	UnauthorizedWorker = 24,
+	NotSubscribed = 25,
+}
+
+impl StratumError {
+	fn to_json(self, message: &str) -> serde_json::Value {
+		json!([self as i32, message, serde_json::Value::Null])
+	}
+}
+
+/// Parses a big-endian hex target, as given in mining.suggest_target, into a difficulty
+fn target_hex_to_diff(hex: &str) -> Result<f64, BadMessageError> {
+	if hex.len() != 64 { return Err(BadMessageError); }
+	let target_be = utils::hex_to_vec(hex).ok_or(BadMessageError)?;
+	let mut target = [0; 32];
+	for (i, b) in target_be.iter().rev().enumerate() {
+		target[i] = *b;
+	}
+	Ok(utils::target_to_diff(&target))
+}
+
+/// Splits a "d=NNN" difficulty hint out of a mining.authorize password, which may be given alone
+/// or alongside a real password, separated by commas or semicolons (eg "secret,d=1024"). Returns
+/// the password without the hint, and the hinted difficulty if there was a valid one. Anything
+/// which isn't a valid hint, including the separators around the rest, is left as it was.
+fn split_password_difficulty(password: &str) -> (String, Option<f64>) {
+	let mut difficulty = None;
+	let mut rest = String::with_capacity(password.len());
+	let mut kept_any = false;
+	let mut separator = None;
+	let mut part_start = 0;
+	for (idx, c) in password.char_indices().chain(Some((password.len(), ','))) {
+		if idx != password.len() && c != ',' && c != ';' { continue; }
+		let part = &password[part_start..idx];
+		let hint = if part.trim().starts_with("d=") {
+			match part.trim()[2..].parse::<f64>() {
+				Ok(diff) if diff > 0.0 && diff.is_finite() => Some(diff),
+				_ => None,
+			}
+		} else { None };
+		match hint {
+			Some(diff) => difficulty = Some(diff),
+			None => {
+				// Each part we keep is joined to the previous one by the separator it had before it
+				if kept_any {
+					if let Some(separator) = separator { rest.push(separator); }
+				}
+				rest.push_str(part);
+				kept_any = true;
+			},
+		}
+		separator = Some(c);
+		part_start = idx + 1;
+	}
+	if difficulty.is_none() { return (password.to_string(), None); }
+	(rest, difficulty)
+}
+
+/// What mining.get_transactions answers with for each of a job's transactions
+#[derive(Clone, Copy)]
+pub enum TransactionsFormat {
+	Txids,
+	/// The full serialized transactions, for clients which check the block contents themselves
+	Hex,
+}
+
+/// How long mining.get_transactions waits for a job's transactions if the job provider hasn't sent
+/// them yet
+const GET_TRANSACTIONS_TIMEOUT_SECS: u64 = 10;
+
+fn transactions_to_json(transactions: &[Transaction], format: TransactionsFormat) -> serde_json::Value {
+	serde_json::Value::Array(transactions.iter().map(|tx| {
+		serde_json::Value::String(match format {
+			TransactionsFormat::Txids => tx.txid().be_hex_string(),
+			TransactionsFormat::Hex => utils::bytes_to_hex(&serialize::serialize(tx).unwrap()),
+		})
+	}).collect())
+}
+
 const EXTRANONCE2_SIZE: usize = 8;
+/// How many messages may be waiting to be written to a client before we give up on it
+const CLIENT_SEND_QUEUE_LEN: usize = 32;
+/// Longest line we accept from a client, well above anything a well-behaved client sends
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+/// How many worker names one connection may authorize
+const MAX_AUTHORIZED_WORKERS: usize = 64;
+/// How long a client we asked to reconnect for a new extranonce2 size has to do so before we close
+/// its connection
+const RECONNECT_GRACE_PERIOD_SECS: u64 = 30;
+/// Consensus limit on the coinbase scriptSig length
+const MAX_COINBASE_SCRIPT_SIG_LEN: usize = 100;
+/// The header version bits miners may roll (BIP320)
 const VERSION_MASK: u32 = 0x1fffe000;
-fn job_to_json_string(template: &BlockTemplate, prev_changed: bool) -> String {
+
+/// Gets the version bits clients may roll on a job, leaving alone any the template itself sets, as
+/// those are signalling for a soft fork deployment
+fn job_version_mask(template: &BlockTemplate) -> u32 {
+	VERSION_MASK & !template.header_version
+}
+
+/// Gets the extranonce2 size for a job, shrinking it from EXTRANONCE2_SIZE if the coinbase prefix and
+/// postfix leave too little room in the scriptSig
+fn job_extranonce2_size(template: &BlockTemplate) -> usize {
+	let used_len = template.coinbase_prefix.len() + 8 + template.coinbase_postfix.len();
+	cmp::min(EXTRANONCE2_SIZE, MAX_COINBASE_SCRIPT_SIG_LEN.saturating_sub(used_len))
+}
+
+fn job_to_json_string(template: &BlockTemplate, extranonce2_size: usize, header_time: u32, prev_changed: bool) -> String {
 	let mut coinbase_prev = String::with_capacity(4*2 + 1*2 + 36*2 + 1*2 + template.coinbase_prefix.len()*2);
 	push_le_32_hex(template.coinbase_version, &mut coinbase_prev);
 	coinbase_prev.push_str("01");
 	coinbase_prev.push_str("0000000000000000000000000000000000000000000000000000000000000000ffffffff");
 	// Add size of extranonce + 8 bytes for client id
-	let coinbase_len = template.coinbase_prefix.len() + 8 + EXTRANONCE2_SIZE + template.coinbase_postfix.len();
+	let coinbase_len = template.coinbase_prefix.len() + 8 + extranonce2_size + template.coinbase_postfix.len();
 	coinbase_prev.push(char::from_digit(((coinbase_len >> 4) & 0x0f) as u32, 16).unwrap());
 	coinbase_prev.push(char::from_digit(((coinbase_len >> 0) & 0x0f) as u32, 16).unwrap());
 	utils::push_bytes_hex(&template.coinbase_prefix[..], &mut coinbase_prev);
@@ -183,7 +287,7 @@ fn job_to_json_string(template: &BlockTemplate, prev_changed: bool) -> String {
 			merkle_rhss,
 			be32_to_hex(template.header_version),
 			be32_to_hex(template.header_nbits),
-			be32_to_hex(template.header_time),
+			be32_to_hex(header_time),
 			prev_changed,
 		],
 		"id": serde_json::Value::Null,
@@ -191,10 +295,10 @@ fn job_to_json_string(template: &BlockTemplate, prev_changed: bool) -> String {
 	}).to_string()
 }
 
-fn job_to_difficulty_string(template: &BlockTemplate) -> String {
+fn difficulty_to_string(difficulty: f64) -> String {
 	json!({
 		"params": [
-			utils::target_to_diff_lb(&template.target),
+			difficulty,
 		],
 		"id": serde_json::Value::Null,
 		"method": "mining.set_difficulty",
@@ -204,68 +308,369 @@ fn job_to_difficulty_string(template: &BlockTemplate) -> String {
 struct StratumClient {
 	stream: mpsc::Sender<String>,
 	client_id: u64,
+	slot: ClientSlot,
 	subscribed: bool,
+	/// When we last sent this client a message of our own accord (ie not a response)
+	last_notify: Instant,
+	vardiff: Vardiff,
+	/// The difficulty we last sent in mining.set_difficulty, and its target
+	sent_difficulty: Option<f64>,
+	share_target: [u8; 32],
+	/// The target before the last mining.set_difficulty, which we still accept shares at as they
+	/// may have been found before the client saw the change
+	prev_share_target: [u8; 32],
+	/// Whether the client sent mining.extranonce.subscribe, and so accepts mining.set_extranonce
+	extranonce_subscribed: bool,
+	/// Fixed for the life of the connection, as it is also what keeps clients' work apart
+	extranonce1: u64,
+	/// The extranonce2 size we last gave the client
+	extranonce2_size: usize,
+	/// When we asked the client to reconnect as it can't take mining.set_extranonce
+	reconnect_requested: Option<Instant>,
+	/// Tears down the client's connection when fired
+	kill_conn: Option<oneshot::Sender<()>>,
+	/// Worker names the client has authorized, which are the only ones it may submit shares under
+	authorized_workers: Vec<String>,
+	/// The version bits the client asked to roll in mining.configure, if it negotiated version
+	/// rolling, and the subset of them we last told it it may roll
+	version_rolling_mask: Option<u32>,
+	version_mask: u32,
+	/// Whether we've yet to answer a mining.get_transactions, as we only take one at a time
+	get_transactions_pending: bool,
+}
+
+impl StratumClient {
+	/// Queues msg to be written to the client, failing if its queue is full, in which case the
+	/// client has stopped reading and would otherwise silently miss messages.
+	fn send(&mut self, msg: String) -> Result<(), ()> {
+		match self.stream.start_send(msg) {
+			Ok(AsyncSink::Ready) => Ok(()),
+			Ok(AsyncSink::NotReady(_)) | Err(_) => Err(()),
+		}
+	}
+
+	/// Sends mining.set_difficulty if the difficulty this client should mine at has changed, which
+	/// is its vardiff difficulty unless the job's own target is easier. Returns whether it was sent.
+	fn update_difficulty(&mut self, job_target: &[u8; 32]) -> Result<bool, ()> {
+		let job_difficulty = utils::target_to_diff(job_target);
+		let difficulty = self.vardiff.get_difficulty().min(job_difficulty);
+		if self.sent_difficulty == Some(difficulty) { return Ok(false); }
+		let diff_string = difficulty_to_string(difficulty);
+		println!("Sending command to {}: {}", self.client_id, diff_string);
+		self.send(diff_string)?;
+		self.sent_difficulty = Some(difficulty);
+		self.prev_share_target = self.share_target;
+		// When mining at the job's difficulty use its target as-is, so that we never reject a share
+		// the pool would take due to rounding
+		self.share_target = if difficulty < job_difficulty { utils::diff_to_target(difficulty) } else { *job_target };
+		Ok(true)
+	}
+
+	fn extranonce1_hex(&self) -> String {
+		let mut extranonce1_str = String::with_capacity(16);
+		push_le_32_hex(self.extranonce1 as u32, &mut extranonce1_str);
+		push_le_32_hex((self.extranonce1 >> 32) as u32, &mut extranonce1_str);
+		extranonce1_str
+	}
+
+	/// Moves the client to a new extranonce2 size via mining.set_extranonce if it supports it, or
+	/// otherwise asks it (once) to reconnect so that it picks up the new size on its next subscribe.
+	/// Only the extranonce2 size changes, the client keeps its extranonce1 either way.
+	/// Returns whether the client can be sent jobs using the new size.
+	fn update_extranonce2_size(&mut self, extranonce2_size: usize, now: Instant) -> Result<bool, ()> {
+		if self.extranonce2_size == extranonce2_size {
+			self.reconnect_requested = None;
+			return Ok(true);
+		}
+		let msg_str = if self.extranonce_subscribed {
+			json!({
+				"params": [self.extranonce1_hex(), extranonce2_size],
+				"id": serde_json::Value::Null,
+				"method": "mining.set_extranonce",
+			}).to_string()
+		} else {
+			if self.reconnect_requested.is_some() { return Ok(false); }
+			json!({
+				"params": [],
+				"id": serde_json::Value::Null,
+				"method": "client.reconnect",
+			}).to_string()
+		};
+		println!("Sending command to {}: {}", self.client_id, msg_str);
+		self.send(msg_str)?;
+		if self.extranonce_subscribed {
+			self.extranonce2_size = extranonce2_size;
+		} else {
+			self.reconnect_requested = Some(now);
+		}
+		Ok(self.extranonce_subscribed)
+	}
+
+	/// Sends mining.set_version_mask if the client negotiated version rolling and the bits it may
+	/// roll have changed
+	fn update_version_mask(&mut self, job_version_mask: u32) -> Result<(), ()> {
+		let version_mask = match self.version_rolling_mask {
+			Some(requested_mask) => requested_mask & job_version_mask,
+			None => return Ok(()),
+		};
+		if self.version_mask == version_mask { return Ok(()); }
+		let msg_str = json!({
+			"params": [be32_to_hex(version_mask)],
+			"id": serde_json::Value::Null,
+			"method": "mining.set_version_mask",
+		}).to_string();
+		println!("Sending command to {}: {}", self.client_id, msg_str);
+		self.send(msg_str)?;
+		self.version_mask = version_mask;
+		Ok(())
+	}
+
+	fn does_hash_meet_share_target(&self, hash: &[u8]) -> bool {
+		utils::does_hash_meet_target(hash, &self.share_target[..]) || utils::does_hash_meet_target(hash, &self.prev_share_target[..])
+	}
+}
+
+/// Settings shared by every worker's StratumServer
+#[derive(Clone)]
+pub struct StratumConfig {
+	pub job_retention: JobRetention,
+	/// How long a client may go without hearing from us before we re-send it the latest job
+	pub renotify_interval: Duration,
+	pub vardiff: VardiffConfig,
+	pub worker_verifier: Arc<WorkerVerifier>,
+	pub transactions_format: TransactionsFormat,
+}
+
+struct StratumJob {
+	work: WorkInfo,
+	received: Instant,
+	extranonce2_size: usize,
+	version_mask: u32,
+	shares: SubmittedShares,
 }
 
 pub struct StratumServer {
 	clients: Vec<Rc<RefCell<StratumClient>>>,
 	client_id_max: u64,
-	// TODO: Limit size of jobs by evicting old ones
-	jobs: BTreeMap<u64, WorkInfo>,
+	/// Shared with the other workers' servers and the main thread, which only need the total
+	clients_connected: Arc<AtomicUsize>,
+	jobs: BTreeMap<u64, StratumJob>,
+	job_retention: JobRetention,
+	vardiff_config: VardiffConfig,
+	duplicate_shares: Arc<DuplicateShareCounts>,
+	worker_verifier: Arc<WorkerVerifier>,
+	transactions_format: TransactionsFormat,
+	timer: Timer,
+	/// The highest job id we've received. Job ids only increase, so any job at or below it which
+	/// isn't in jobs has been evicted.
+	last_job_id: Option<u64>,
 }
 
 impl StratumServer {
-	pub fn new(job_providers: mpsc::Receiver<WorkInfo>) -> Rc<RefCell<Self>> {
+	pub fn new(job_providers: sync_mpsc::Receiver<WorkInfo>, worker_id: u16, clients_connected: Arc<AtomicUsize>, config: StratumConfig, duplicate_shares: Arc<DuplicateShareCounts>, timer: Timer) -> Rc<RefCell<Self>> {
 		let us = Rc::new(RefCell::new(Self {
 			clients: Vec::new(),
-			client_id_max: 0,
+			client_id_max: (worker_id as u64) << 48,
+			clients_connected: clients_connected,
 			jobs: BTreeMap::new(),
+			job_retention: config.job_retention,
+			vardiff_config: config.vardiff,
+			duplicate_shares: duplicate_shares,
+			worker_verifier: config.worker_verifier,
+			transactions_format: config.transactions_format,
+			timer: timer,
+			last_job_id: None,
 		}));
 
 		let us_cp = us.clone();
 		let mut last_prevblock = [0; 32];
-		let mut last_diff = [0; 32];
 		current_thread::spawn(job_providers.for_each(move |job| {
-			macro_rules! announce_str {
-				($str: expr) => {
-					us_cp.borrow_mut().clients.retain(|ref it| {
-						let mut client = it.borrow_mut();
-						if !client.subscribed { return true; }
-						match client.stream.start_send($str.clone()) {
-							Ok(_) => true,
-							Err(_) => false
-						}
-					});
-				}
-			}
-
 			let prev_changed = last_prevblock != job.template.header_prevblock;
 			if prev_changed {
 				last_prevblock = job.template.header_prevblock;
 			}
-			let diff_changed = last_diff != job.template.target;
-			if diff_changed {
-				last_diff = job.template.target;
-				let diff_str = job_to_difficulty_string(&job.template);
-				announce_str!(diff_str);
-			}
-
-			let job_json = job_to_json_string(&job.template, prev_changed);
-			announce_str!(job_json);
-			us_cp.borrow_mut().jobs.insert(job.template.template_id, job);
+			us_cp.borrow_mut().announce_job(job, prev_changed, Instant::now());
 			future::result(Ok(()))
 		}));
 
 		us
 	}
 
-	pub fn new_connection(rc: Rc<RefCell<Self>>, stream: net::TcpStream) {
-		stream.set_nodelay(true).unwrap();
+	/// Sends a new job to all subscribed clients, first updating their difficulty and extranonce2
+	/// size if needed
+	fn announce_job(&mut self, job: WorkInfo, prev_changed: bool, now: Instant) {
+		let extranonce2_size = job_extranonce2_size(&job.template);
+		let version_mask = job_version_mask(&job.template);
+		let job_json = job_to_json_string(&job.template, extranonce2_size, job.template.header_time, prev_changed);
+		self.clients.retain(|ref it| {
+			let mut client = it.borrow_mut();
+			if !client.subscribed { return true; }
+			client.last_notify = now;
+			match client.update_extranonce2_size(extranonce2_size, now) {
+				Ok(true) => {},
+				Ok(false) => return true,
+				Err(_) => return false,
+			}
+			if client.update_version_mask(version_mask).is_err() { return false; }
+			if client.update_difficulty(&job.template.target).is_err() { return false; }
+			match client.send(job_json.clone()) {
+				Ok(_) => true,
+				Err(_) => false
+			}
+		});
+		self.last_job_id = Some(job.template.template_id);
+		self.jobs.insert(job.template.template_id, StratumJob {
+			work: job,
+			received: now,
+			extranonce2_size: extranonce2_size,
+			version_mask: version_mask,
+			shares: SubmittedShares::new(),
+		});
+		self.evict_jobs(now);
+	}
+
+	/// Drops jobs which fall outside of job_retention or are built on a previous block
+	fn evict_jobs(&mut self, now: Instant) {
+		self.job_retention.evict(&mut self.jobs, now, |job| (job.work.template.header_prevblock, job.received));
+	}
 
-		let (tx, rx) = stream.framed(codec::LinesCodec::new()).split();
+	/// Gets the latest job's target, its extranonce2 size and a mining.notify for it (without
+	/// clearing old jobs), with ntime moved forward to now
+	fn latest_job_renotify(&self, now: Instant) -> Option<([u8; 32], usize, String)> {
+		self.jobs.iter().next_back().map(|(_, job)| {
+			let elapsed_secs = now.duration_since(job.received).as_secs() as u32;
+			(job.work.template.target, job.extranonce2_size,
+				job_to_json_string(&job.work.template, job.extranonce2_size, job.work.template.header_time.wrapping_add(elapsed_secs), false))
+		})
+	}
+
+	/// Re-sends the latest job, with ntime moved forward to now, to each subscribed client we haven't
+	/// sent anything to in idle_interval, as otherwise many stratum clients assume we've gone away.
+	pub fn renotify_idle_clients(&mut self, idle_interval: Duration, now: Instant) {
+		let (extranonce2_size, job_json) = match self.latest_job_renotify(now) {
+			Some((_, extranonce2_size, job_json)) => (extranonce2_size, job_json),
+			None => return,
+		};
+		self.clients.retain(|ref it| {
+			let mut client = it.borrow_mut();
+			// Clients we've asked to reconnect for a new extranonce2 size can't use the latest job
+			if !client.subscribed || client.extranonce2_size != extranonce2_size { return true; }
+			if now.duration_since(client.last_notify) < idle_interval { return true; }
+			client.last_notify = now;
+			match client.send(job_json.clone()) {
+				Ok(_) => true,
+				Err(_) => false
+			}
+		});
+	}
+
+	/// Closes the connections of clients we asked to reconnect for a new extranonce2 size which still
+	/// haven't done so, as they can't be sent any jobs until they do.
+	pub fn close_unreconnected_clients(&mut self, now: Instant) {
+		for client in self.clients.iter() {
+			let mut client = client.borrow_mut();
+			let expired = match client.reconnect_requested {
+				Some(requested) => now.duration_since(requested) >= Duration::from_secs(RECONNECT_GRACE_PERIOD_SECS),
+				None => false,
+			};
+			if expired {
+				if let Some(kill_conn) = client.kill_conn.take() {
+					println!("Client {} didn't reconnect for a new extranonce2 size, disconnecting", client.client_id);
+					let _ = kill_conn.send(());
+				}
+			}
+		}
+	}
+
+	/// Moves a client to a difficulty it suggested, within our vardiff bounds. If it has subscribed,
+	/// it is sent the new difficulty and the latest job right away.
+	fn apply_suggested_difficulty(&self, client: &mut StratumClient, difficulty: f64, now: Instant) -> Result<(), ()> {
+		let difficulty = client.vardiff.suggest(&self.vardiff_config, difficulty, now);
+		println!("Client {} suggested a difficulty, moving it to {}", client.client_id, difficulty);
+		self.send_new_difficulty(client, now)
+	}
+
+	/// Raises the least difficulty a client is given to one it asked for in mining.configure, sending
+	/// it the new difficulty right away if that means it has to go up.
+	fn apply_minimum_difficulty(&self, client: &mut StratumClient, min_difficulty: f64, now: Instant) -> Result<(), ()> {
+		match client.vardiff.set_minimum(&self.vardiff_config, min_difficulty) {
+			Some(difficulty) => {
+				println!("Client {} asked for a minimum difficulty, moving it to {}", client.client_id, difficulty);
+				self.send_new_difficulty(client, now)
+			},
+			None => Ok(()),
+		}
+	}
+
+	/// Sends a subscribed client whose vardiff difficulty we've just changed the new difficulty and
+	/// the latest job, so that it starts using it.
+	fn send_new_difficulty(&self, client: &mut StratumClient, now: Instant) -> Result<(), ()> {
+		if !client.subscribed { return Ok(()); }
+		let (job_target, extranonce2_size, job_json) = match self.latest_job_renotify(now) {
+			Some(job) => job,
+			None => return Ok(()),
+		};
+		if !client.update_difficulty(&job_target)? || client.extranonce2_size != extranonce2_size { return Ok(()); }
+		client.last_notify = now;
+		match client.send(job_json) {
+			Ok(_) => Ok(()),
+			Err(_) => Err(()),
+		}
+	}
+
+	/// Retargets clients whose vardiff window has ended, which catches clients that have stopped
+	/// finding shares at all. Clients which get a new difficulty are re-sent the latest job so that
+	/// they start using it.
+	pub fn retarget_clients(&mut self, now: Instant) {
+		let (job_target, extranonce2_size, job_json) = match self.latest_job_renotify(now) {
+			Some(job) => job,
+			None => return,
+		};
+		let vardiff_config = self.vardiff_config;
+		self.clients.retain(|ref it| {
+			let mut client = it.borrow_mut();
+			if !client.subscribed || client.extranonce2_size != extranonce2_size { return true; }
+			if client.vardiff.maybe_retarget(&vardiff_config, utils::target_to_diff(&job_target), now).is_none() { return true; }
+			match client.update_difficulty(&job_target) {
+				Ok(true) => {},
+				Ok(false) => return true,
+				Err(_) => return false,
+			}
+			client.last_notify = now;
+			match client.send(job_json.clone()) {
+				Ok(_) => true,
+				Err(_) => false
+			}
+		});
+	}
+
+	/// Asks all clients to move to host:port via client.reconnect
+	pub fn send_reconnect(&mut self, host: &str, port: u16) {
+		let msg_str = json!({
+			"params": [host, port, 0],
+			"id": serde_json::Value::Null,
+			"method": "client.reconnect",
+		}).to_string();
+		for client in self.clients.iter() {
+			let _ = client.borrow_mut().send(msg_str.clone());
+		}
+	}
+
+	pub fn new_connection<S: AsyncRead + AsyncWrite + 'static>(rc: Rc<RefCell<Self>>, stream: S, slot: ClientSlot) {
+		let (tx, rx) = stream.framed(LinesCodec::new_with_max_length(MAX_LINE_LENGTH)).split();
+		// LinesCodec reports overlong lines as Other, but they're a protocol violation like any
+		// other garbage the client sends us
+		let rx = rx.map_err(|e| {
+			if e.kind() == io::ErrorKind::Other {
+				io::Error::new(io::ErrorKind::InvalidData, e)
+			} else {
+				e
+			}
+		});
+		let (kill_tx, kill_rx) = oneshot::channel();
 
 		let client_ref = {
-			let (send_sink, send_stream) = mpsc::channel(5);
+			let (send_sink, send_stream) = mpsc::channel(CLIENT_SEND_QUEUE_LEN);
 			current_thread::spawn(tx.send_all(send_stream.map_err(|_| -> io::Error {
 				panic!("mpsc streams cant generate errors!");
 			})).then(|_| {
@@ -276,24 +681,41 @@ impl StratumServer {
 			let client = Rc::new(RefCell::new(StratumClient {
 				stream: send_sink,
 				client_id: us.client_id_max,
+				slot: slot,
 				subscribed: false,
+				last_notify: Instant::now(),
+				vardiff: Vardiff::new(&us.vardiff_config, Instant::now()),
+				sent_difficulty: None,
+				share_target: [0xff; 32],
+				prev_share_target: [0xff; 32],
+				extranonce_subscribed: false,
+				extranonce1: us.client_id_max,
+				extranonce2_size: EXTRANONCE2_SIZE,
+				reconnect_requested: None,
+				kill_conn: Some(kill_tx),
+				authorized_workers: Vec::new(),
+				version_rolling_mask: None,
+				version_mask: 0,
+				get_transactions_pending: false,
 			}));
 			println!("Got new client connection (id {})", us.client_id_max);
 			us.client_id_max += 1;
 
 			let client_ref = client.clone();
 			us.clients.push(client);
+			us.clients_connected.fetch_add(1, Ordering::AcqRel);
 			client_ref
 		};
 
 		let rc_close = rc.clone();
 		let client_ref_close = client_ref.clone();
 
-		//TODO: Set a timer for the client to always push *something* to them every 30 seconds or
-		//so, as otherwise stratum clients time out.
-
 		current_thread::spawn(rx.for_each(move |line| -> future::FutureResult<(), io::Error> {
 			println!("Got line from {}: {}", client_ref.borrow().client_id, line);
+			if !client_ref.borrow_mut().slot.message_received() {
+				println!("Client {} exceeded the message rate limit", client_ref.borrow().client_id);
+				return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
+			}
 			let json = match serde_json::from_str::<serde_json::Value>(&line) {
 				Ok(v) => {
 					if !v.is_object() {
@@ -307,10 +729,8 @@ impl StratumServer {
 				return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
 			}
 			let msg = json.as_object().unwrap();
-			if !msg.contains_key("method") || !msg.contains_key("id") || !msg.contains_key("params") {
-				return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
-			}
-			if !msg["method"].is_string() {
+			// Without an id we can't tell the client what it got wrong
+			if !msg.contains_key("id") {
 				return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
 			}
 
@@ -324,86 +744,116 @@ impl StratumServer {
 						"result": $res,
 					}).to_string();
 					println!("Sending command to {}: {}", client.client_id, msg_str);
-					match client.stream.start_send(msg_str) {
+					match client.send(msg_str) {
 						Err(_) => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError))),
 						Ok(_) => {}
 					}
 				}
 			}
 
-			match msg["method"].as_str().unwrap() {
+			// Responds with an error and moves on to the client's next message, leaving it connected
+			macro_rules! send_error {
+				($err: expr, $message: expr) => {
+					send_response!($err.to_json($message), (serde_json::Value::Null));
+					return future::result(Ok(()));
+				}
+			}
+
+			match msg["method"].as_str().unwrap_or("") {
 				"mining.subscribe" => {
-					let mut client_id_str = String::with_capacity(16);
-					push_le_32_hex(client.client_id as u32, &mut client_id_str);
-					push_le_32_hex((client.client_id >> 32) as u32, &mut client_id_str);
+					let client_id_str = client.extranonce1_hex();
+					client.extranonce2_size = match rc.borrow().jobs.iter().next_back() {
+						Some((_, job)) => job.extranonce2_size,
+						None => EXTRANONCE2_SIZE,
+					};
 					send_response!(serde_json::Value::Null,
 						[
 							[ "mining.notify", &client_id_str ],
 							client_id_str,
-							EXTRANONCE2_SIZE,
+							client.extranonce2_size,
 						]);
-					match rc.borrow().jobs.iter().last() { //TODO: This is ineffecient, map should have a last()
+					match rc.borrow().jobs.iter().next_back() {
 						Some(job) => {
-							let diff_string = job_to_difficulty_string(&job.1.template);
-							println!("Sending command to {}: {}", client.client_id, diff_string);
-							match client.stream.start_send(diff_string) {
-								Err(_) => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError))),
-								Ok(_) => {}
+							if client.update_version_mask(job.1.version_mask).is_err() || client.update_difficulty(&job.1.work.template.target).is_err() {
+								return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
 							}
-							let job_string = job_to_json_string(&job.1.template, true);
+							let job_string = job_to_json_string(&job.1.work.template, job.1.extranonce2_size, job.1.work.template.header_time, true);
 							println!("Sending command to {}: {}", client.client_id, job_string);
-							match client.stream.start_send(job_string) {
+							match client.send(job_string) {
 								Err(_) => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError))),
 								Ok(_) => {}
 							}
 						}, None => {}
 					}
 					client.subscribed = true;
+					client.last_notify = Instant::now();
 				},
 				"mining.submit" => {
-					if !msg["params"].is_array() || msg["params"].as_array().unwrap().len() < 5 {
-						return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
+					if !client.subscribed {
+						send_error!(StratumError::NotSubscribed, "Not subscribed");
+					}
+					if !json["params"].is_array() || json["params"].as_array().unwrap().len() < 5 {
+						send_error!(StratumError::Other, "Malformed submit");
 					}
-					let params = msg["params"].as_array().unwrap();
+					let params = json["params"].as_array().unwrap();
 					for (idx, param) in params.iter().enumerate() {
 						if !param.is_string() {
-							return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
-						}
-						if idx == 2 && param.as_str().unwrap().len() != EXTRANONCE2_SIZE*2 {
-							return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
+							send_error!(StratumError::Other, "Malformed submit");
 						}
 						if (idx == 3 || idx == 4) && param.as_str().unwrap().len() != 8 {
-							return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
+							send_error!(StratumError::Other, "Malformed submit");
 						}
 					}
 
+					let worker = match client.authorized_workers.iter().find(|worker| *worker == params[0].as_str().unwrap()) {
+						Some(worker) => worker.clone(),
+						None => {
+							println!("Client {} submitted a share for unauthorized worker {}", client.client_id, params[0].as_str().unwrap());
+							send_error!(StratumError::UnauthorizedWorker, "Unauthorized worker");
+						}
+					};
+
 					let job_id = match params[1].as_str().unwrap().parse() {
-						Err(_) => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError))),
+						Err(_) => { send_error!(StratumError::JobNotFound, "Job not found"); },
 						Ok(id) => id,
 					};
 					let time = match hex_to_be32(params[3].as_str().unwrap()) {
-						Err(_) => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError))),
+						Err(_) => { send_error!(StratumError::Other, "Malformed submit"); },
 						Ok(time) => time,
 					};
 					let nonce = match hex_to_be32(params[4].as_str().unwrap()) {
-						Err(_) => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError))),
+						Err(_) => { send_error!(StratumError::Other, "Malformed submit"); },
 						Ok(nonce) => nonce,
 					};
 
-					match rc.borrow().jobs.get(&job_id) {
-						Some(job) => {
+					rc.borrow_mut().evict_jobs(Instant::now());
+					let us = rc.borrow();
+					match us.jobs.get(&job_id) {
+						Some(&StratumJob { work: ref job, extranonce2_size, version_mask: job_version_mask, ref shares, .. }) => {
+							if params[2].as_str().unwrap().len() != extranonce2_size*2 {
+								send_error!(StratumError::Other, "Incorrect size of extranonce2");
+							}
+							// BIP310 clients send the bits they rolled, which must be within the mask
+							// they negotiated for this job
 							let version = if params.len() >= 6 {
 								match hex_to_be32(params[5].as_str().unwrap()) {
-									Err(_) => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError))),
-									Ok(version) => (version & VERSION_MASK) | (job.template.header_version & !VERSION_MASK),
+									Err(_) => { send_error!(StratumError::Other, "Malformed submit"); },
+									Ok(version_bits) => {
+										let version_mask = client.version_rolling_mask.unwrap_or(0) & job_version_mask;
+										if version_bits & !version_mask != 0 {
+											println!("Client {} rolled version bits {:08x} outside of its mask {:08x}", client.client_id, version_bits, version_mask);
+											send_error!(StratumError::Other, "Version bits outside of negotiated mask");
+										}
+										(job.template.header_version & !version_mask) | version_bits
+									},
 								}
 							} else { job.template.header_version };
 
 							let mut script_sig = job.template.coinbase_prefix.clone();
-							script_sig.extend_from_slice(&utils::le64_to_array(client.client_id));
-							match extend_vec_from_hex(params[2].as_str().unwrap(), &mut script_sig) {
-								Ok(_) => {},
-								Err(_) => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError))),
+							script_sig.extend_from_slice(&utils::le64_to_array(client.extranonce1));
+							match utils::hex_to_vec(params[2].as_str().unwrap()) {
+								Some(extranonce2) => script_sig.extend_from_slice(&extranonce2),
+								None => { send_error!(StratumError::Other, "Malformed submit"); },
 							}
 							script_sig.extend_from_slice(&job.template.coinbase_postfix[..]);
 
@@ -442,11 +892,28 @@ impl StratumServer {
 								nonce: nonce,
 							}.bitcoin_hash();
 
-							let user_tag_bytes = params[0].as_str().unwrap().as_bytes();
+							let user_tag_bytes = worker.as_bytes();
 							let user_tag = user_tag_bytes[0..cmp::min(user_tag_bytes.len(), 255)].to_vec();
 
-							if utils::does_hash_meet_target(&block_hash[..], &job.template.target[..]) {
-								match job.solutions.unbounded_send(Rc::new((WinningNonce {
+							if !client.does_hash_meet_share_target(&block_hash[..]) {
+								println!("Got work that missed target (hashed to {}, which is greater than {})", utils::bytes_to_hex(&block_hash[..]), utils::bytes_to_hex(&client.share_target[..]));
+								send_error!(StratumError::LowDifficulty, "Low difficulty share");
+							}
+							if !shares.check_new(&block_hash[..]) {
+								println!("Got duplicate share from {} (worker {})", client.client_id, worker);
+								us.duplicate_shares.record(&worker);
+								send_error!(StratumError::DuplicateShare, "Duplicate share");
+							}
+
+							// Shares which only meet the client's vardiff target are accepted but not
+							// worth sending upstream. The job target should never be harder than the
+							// network's, but never drop a block if it somehow is.
+							let meets_network_target = match utils::nbits_to_target(job.template.header_nbits) {
+								Some(network_target) => utils::does_hash_meet_target(&block_hash[..], &network_target[..]),
+								None => false,
+							};
+							if meets_network_target || utils::does_hash_meet_target(&block_hash[..], &job.template.target[..]) {
+								match job.solutions.unbounded_send(Arc::new((WinningNonce {
 									template_id: job.template.template_id,
 									header_version: version,
 									header_time: time,
@@ -457,74 +924,879 @@ impl StratumServer {
 									Ok(_) => {},
 									Err(_) => { panic!(); },
 								};
-								send_response!(serde_json::Value::Null, true);
-							} else {
-								println!("Got work that missed target (hashed to {}, which is greater than {})", utils::bytes_to_hex(&block_hash[..]), utils::bytes_to_hex(&job.template.target[..]));
-								send_response!(serde_json::Value::Null, false);
+							}
+							send_response!(serde_json::Value::Null, true);
+
+							let latest_target = us.jobs.iter().next_back().unwrap().1.work.template.target;
+							let difficulty_sent = if client.vardiff.share_accepted(&us.vardiff_config, utils::target_to_diff(&latest_target), Instant::now()).is_some() {
+								match client.update_difficulty(&latest_target) {
+									Ok(sent) => sent,
+									Err(_) => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError))),
+								}
+							} else { false };
+							if difficulty_sent {
+								let (_, latest_extranonce2_size, latest_json) = us.latest_job_renotify(Instant::now()).unwrap();
+								if client.extranonce2_size == latest_extranonce2_size {
+									client.last_notify = Instant::now();
+									match client.send(latest_json) {
+										Err(_) => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError))),
+										Ok(_) => {}
+									}
+								}
 							}
 						},
+						None if us.last_job_id.map(|last_id| job_id <= last_id).unwrap_or(false) => {
+							send_error!(StratumError::JobNotFound, "Job not found (=stale)");
+						},
 						None => {
-							send_response!("Invalid job_id or job timed out", false);
+							send_error!(StratumError::JobNotFound, "Job not found");
 						},
 					}
 				},
-				"mining.authorize" => {
+				"mining.suggest_difficulty"|"mining.suggest_target" => {
+					let difficulty = match json["params"].as_array().and_then(|params| params.get(0)) {
+						Some(param) if msg["method"] == "mining.suggest_difficulty" => match param.as_f64() {
+							Some(diff) if diff > 0.0 && diff.is_finite() => diff,
+							_ => { send_error!(StratumError::Other, "Invalid difficulty"); },
+						},
+						Some(param) => match param.as_str().map(target_hex_to_diff) {
+							Some(Ok(diff)) if diff > 0.0 && diff.is_finite() => diff,
+							_ => { send_error!(StratumError::Other, "Invalid target"); },
+						},
+						None => { send_error!(StratumError::Other, "Missing difficulty"); },
+					};
+					if rc.borrow().apply_suggested_difficulty(&mut client, difficulty, Instant::now()).is_err() {
+						return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
+					}
 					send_response!(serde_json::Value::Null, true);
 				},
-				"mining.get_transactions" => {
-					send_response!(serde_json::Value::Null, []);
+				"mining.extranonce.subscribe" => {
+					client.extranonce_subscribed = true;
+					send_response!(serde_json::Value::Null, true);
 				},
-				"mining.configure" => {
-					if !msg["params"].is_array() || msg["params"].as_array().unwrap().len() != 2 {
-						return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
+				"mining.authorize" => {
+					if !json["params"].is_array() || json["params"].as_array().unwrap().is_empty() {
+						send_error!(StratumError::Other, "Malformed authorize");
 					}
-					let params = msg["params"].as_array().unwrap();
-					if !params[0].is_array() || params[1].is_object() {
-						return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
+					let params = json["params"].as_array().unwrap();
+					let worker = match params[0].as_str() {
+						Some(worker) => worker,
+						None => { send_error!(StratumError::Other, "Malformed authorize"); },
+					};
+					// Many miners send a null or missing password
+					let password = match params.get(1) {
+						Some(&serde_json::Value::String(ref password)) => &password[..],
+						Some(&serde_json::Value::Null) | None => "",
+						Some(_) => { send_error!(StratumError::Other, "Malformed authorize"); },
+					};
+					let (password, suggested_difficulty) = split_password_difficulty(password);
+					if rc.borrow().worker_verifier.verify(worker, &password) {
+						if !client.authorized_workers.iter().any(|authorized| authorized == worker) {
+							if client.authorized_workers.len() >= MAX_AUTHORIZED_WORKERS {
+								println!("Client {} tried to authorize more than {} workers", client.client_id, MAX_AUTHORIZED_WORKERS);
+								send_error!(StratumError::Other, "Too many workers");
+							}
+							client.authorized_workers.push(worker.to_string());
+						}
+						send_response!(serde_json::Value::Null, true);
+						if let Some(difficulty) = suggested_difficulty {
+							if rc.borrow().apply_suggested_difficulty(&mut client, difficulty, Instant::now()).is_err() {
+								return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
+							}
+						}
+					} else {
+						println!("Client {} failed to authorize as worker {}", client.client_id, worker);
+						send_error!(StratumError::UnauthorizedWorker, "Unauthorized worker");
+					}
+				},
+				"mining.get_transactions" => {
+					if client.get_transactions_pending {
+						send_error!(StratumError::Other, "Already waiting on transactions");
 					}
-					for ext in params[0].as_array().unwrap().iter() {
+					// Takes the job id, defaulting to the latest job if none is given
+					let us = rc.borrow();
+					let job = match json["params"].as_array().and_then(|params| params.get(0)) {
+						Some(job_id) => match job_id.as_str().and_then(|id| id.parse().ok()) {
+							Some(job_id) => us.jobs.get(&job_id),
+							None => { send_error!(StratumError::Other, "Malformed get_transactions"); },
+						},
+						None => us.jobs.values().next_back(),
+					};
+					let transactions = match job {
+						Some(job) => job.work.transactions.clone(),
+						None => { send_error!(StratumError::JobNotFound, "Job not found"); },
+					};
+
+					// The transactions usually arrive shortly after the job, so we may have to wait
+					let id = msg["id"].clone();
+					let format = us.transactions_format;
+					let client_id = client.client_id;
+					let stream = client.stream.clone();
+					client.get_transactions_pending = true;
+					let client_ref_txn = client_ref.clone();
+					let timeout = us.timer.sleep(Duration::from_secs(GET_TRANSACTIONS_TIMEOUT_SECS));
+					current_thread::spawn(transactions.map_err(|_| ()).select2(timeout.map_err(|_| ())).then(move |res| {
+						client_ref_txn.borrow_mut().get_transactions_pending = false;
+						let msg_str = match res {
+							Ok(future::Either::A((transactions, _))) => json!({
+								"error": serde_json::Value::Null,
+								"id": id,
+								"result": transactions_to_json(&transactions, format),
+							}),
+							_ => {
+								println!("Client {} requested transactions which we don't have", client_id);
+								json!({
+									"error": StratumError::Other.to_json("Transactions not available"),
+									"id": id,
+									"result": serde_json::Value::Null,
+								})
+							},
+						}.to_string();
+						println!("Sending command to {}: {}", client_id, msg_str);
+						stream.send(msg_str).then(|_| {
+							future::result(Ok(()))
+						})
+					}));
+				},
+				"mining.configure" => {
+					// BIP310: params are the extensions the client wants and an object of their
+					// parameters. Each extension gets a result, false if we don't support it.
+					let (extensions, ext_params) = match (json["params"][0].as_array(), json["params"][1].as_object()) {
+						(Some(extensions), Some(ext_params)) => (extensions, ext_params),
+						_ => { send_error!(StratumError::Other, "Malformed configure"); },
+					};
+					let mut result = serde_json::Map::new();
+					for ext in extensions.iter() {
 						match ext.as_str() {
 							Some("version-rolling") => {
-								match params[1].as_object().unwrap().get("version-rolling.mask") {
-									Some(ref mask) => {
-										let mask_value: u32 = match mask.as_str() {
-											Some(mask_str) => {
-												match mask_str.parse() {
-													Err(_) => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError))),
-													Ok(v) => v,
-												}
-											},
-											None => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)))
-										};
-										send_response!(serde_json::Value::Null, {
-											"version-rolling": true,
-											"version-rolling.mask": be32_to_hex(mask_value & VERSION_MASK),
-										});
-										return future::result(Ok(()))
+								// Without a mask the client can roll any bits we allow
+								let requested_mask = match ext_params.get("version-rolling.mask") {
+									Some(mask) => match mask.as_str().and_then(|mask| u32::from_str_radix(mask, 16).ok()) {
+										Some(mask) => mask,
+										None => { send_error!(StratumError::Other, "Malformed configure"); },
 									},
-									None => {}
+									None => 0xffffffff,
+								};
+								let job_version_mask = match rc.borrow().jobs.values().next_back() {
+									Some(job) => job.version_mask,
+									None => VERSION_MASK,
+								};
+								client.version_rolling_mask = Some(requested_mask);
+								client.version_mask = requested_mask & job_version_mask;
+								result.insert("version-rolling".to_string(), json!(true));
+								result.insert("version-rolling.mask".to_string(), json!(be32_to_hex(client.version_mask)));
+							},
+							Some("minimum-difficulty") => {
+								let min_difficulty = match ext_params.get("minimum-difficulty.value").and_then(|value| value.as_f64()) {
+									Some(diff) if diff >= 0.0 && diff.is_finite() => diff,
+									_ => { send_error!(StratumError::Other, "Malformed configure"); },
 								};
+								if rc.borrow().apply_minimum_difficulty(&mut client, min_difficulty, Instant::now()).is_err() {
+									return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
+								}
+								result.insert("minimum-difficulty".to_string(), json!(true));
+							},
+							Some("subscribe-extranonce") => {
+								client.extranonce_subscribed = true;
+								result.insert("subscribe-extranonce".to_string(), json!(true));
+							},
+							Some(ext) => {
+								result.insert(ext.to_string(), json!(false));
 							},
 							None => {
-								return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
-							}
-							_ => {},
+								send_error!(StratumError::Other, "Malformed configure");
+							},
 						}
 					}
-					send_response!(serde_json::Value::Null, {});
+					send_response!(serde_json::Value::Null, (serde_json::Value::Object(result)));
 				},
 				_ => {
-					return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)))
+					// Odd firmware sends all sorts of things, and is better off mining than disconnected
+					println!("Client {} sent unknown method {}", client.client_id, msg["method"]);
+					send_error!(StratumError::Other, "Unknown method");
 				}
 			};
 			future::result(Ok(()))
-		}).then(move |_| {
+		}).select(kill_rx.then(|_| -> Result<(), io::Error> {
+			Err(io::Error::new(io::ErrorKind::TimedOut, BadMessageError))
+		})).then(move |res| {
+			// We close connections with InvalidData when the client misbehaves
+			match res {
+				Err((ref e, _)) if e.kind() == io::ErrorKind::InvalidData => client_ref_close.borrow().slot.protocol_violation(),
+				_ => {},
+			}
 			let mut us = rc_close.borrow_mut();
 			us.clients.retain(|client| {
 				!Rc::ptr_eq(&client_ref_close, client)
 			});
+			us.clients_connected.fetch_sub(1, Ordering::AcqRel);
 			println!("Client {} disconnected, now have {} clients!", client_ref_close.borrow().client_id, us.clients.len());
 			future::result(Ok(()))
 		}));
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use auth::AllowAllWorkers;
+	use limits::{ClientLimiter,LimitsConfig};
+	use msg_framing::{BlockTemplate,JobTransactions,WorkInfo};
+	use stratum_server::*;
+	use shares::{DuplicateShareCounts,JobRetention,SubmittedShares};
+	use utils;
+	use vardiff::{Vardiff,VardiffConfig};
+
+	use bitcoin::blockdata::transaction::TxOut;
+
+	use futures::Async;
+	use futures::executor::{self,Notify,Spawn};
+	use futures::Future;
+	use futures::sync::mpsc as sync_mpsc;
+	use futures::sync::oneshot as sync_oneshot;
+	use futures::unsync::{mpsc,oneshot};
+
+	use serde_json;
+
+	use tokio_uds;
+
+	use std::cell::RefCell;
+	use std::collections::BTreeMap;
+	use std::net::IpAddr;
+	use std::rc::Rc;
+	use std::sync::Arc;
+	use std::sync::atomic::AtomicUsize;
+	use std::time::{Duration,Instant};
+
+	struct NoopNotify;
+	impl Notify for NoopNotify {
+		fn notify(&self, _: usize) {}
+	}
+
+	/// Gets everything queued for sending to a client, without blocking
+	fn sent_messages(rx: &mut Spawn<mpsc::Receiver<String>>) -> Vec<serde_json::Value> {
+		let notify = Arc::new(NoopNotify);
+		let mut res = Vec::new();
+		while let Ok(Async::Ready(Some(msg))) = rx.poll_stream_notify(&notify, 0) {
+			res.push(serde_json::from_str(&msg).unwrap());
+		}
+		res
+	}
+
+	/// Transactions for a job whose TransactionData never arrives
+	fn no_transactions() -> JobTransactions {
+		let (_, rx) = sync_oneshot::channel();
+		rx.shared()
+	}
+
+	fn test_template() -> BlockTemplate {
+		BlockTemplate {
+			template_id: 1,
+			target: [0xff; 32],
+			header_version: 0x20000000,
+			header_prevblock: [0x11; 32],
+			header_time: 1_500_000_000,
+			header_nbits: 0x1d00ffff,
+			merkle_rhss: Vec::new(),
+			coinbase_value_remaining: 0,
+			coinbase_version: 1,
+			coinbase_prefix: Vec::new(),
+			coinbase_postfix: Vec::new(),
+			coinbase_input_sequence: 0xffffffff,
+			appended_coinbase_outputs: Vec::new(),
+			coinbase_locktime: 0,
+		}
+	}
+
+	/// test_template with a pool difficulty of 2^20, so that shares at a client's difficulty don't
+	/// become solutions
+	fn test_template_with_target() -> BlockTemplate {
+		let mut template = test_template();
+		template.target = [0; 32];
+		template.target[25] = 0x10;
+		template
+	}
+
+	/// Builds a server with template as its only job and two clients, only the first of which has
+	/// subscribed, returning the server and what's sent to each client
+	fn test_server(template: BlockTemplate, vardiff_config: VardiffConfig, start: Instant) -> (StratumServer, Vec<Spawn<mpsc::Receiver<String>>>) {
+		let limiter = ClientLimiter::new(LimitsConfig::default());
+		let (solutions, _solutions_rx) = sync_mpsc::unbounded();
+
+		let mut server = StratumServer {
+			clients: Vec::new(),
+			client_id_max: 2,
+			clients_connected: Arc::new(AtomicUsize::new(2)),
+			jobs: BTreeMap::new(),
+			job_retention: JobRetention::default(),
+			vardiff_config: vardiff_config,
+			duplicate_shares: DuplicateShareCounts::new(),
+			worker_verifier: Arc::new(AllowAllWorkers),
+			transactions_format: TransactionsFormat::Txids,
+			timer: Timer::default(),
+			last_job_id: Some(1),
+		};
+		server.jobs.insert(1, StratumJob {
+			extranonce2_size: job_extranonce2_size(&template),
+			version_mask: job_version_mask(&template),
+			shares: SubmittedShares::new(),
+			work: WorkInfo { template: Arc::new(template), solutions: solutions, transactions: no_transactions() },
+			received: start,
+		});
+		let mut receivers = Vec::new();
+		for client_id in 0..2 {
+			let (tx, rx) = mpsc::channel(CLIENT_SEND_QUEUE_LEN);
+			server.clients.push(Rc::new(RefCell::new(StratumClient {
+				stream: tx,
+				client_id: client_id,
+				slot: ClientLimiter::try_accept(&limiter, None).unwrap(),
+				subscribed: client_id == 0,
+				last_notify: start,
+				vardiff: Vardiff::new(&vardiff_config, start),
+				sent_difficulty: None,
+				share_target: [0xff; 32],
+				prev_share_target: [0xff; 32],
+				extranonce_subscribed: false,
+				extranonce1: client_id,
+				extranonce2_size: EXTRANONCE2_SIZE,
+				reconnect_requested: None,
+				kill_conn: None,
+				authorized_workers: Vec::new(),
+				version_rolling_mask: None,
+				version_mask: 0,
+				get_transactions_pending: false,
+			})));
+			receivers.push(executor::spawn(rx));
+		}
+		(server, receivers)
+	}
+
+	/// Sends each line over a new connection to server, waiting for the response to each before
+	/// sending the next, and returns the responses, the notifications sent along the way and how
+	/// many clients the server still has once they've all been answered. Lines joined by newlines
+	/// are sent at once, and we wait for a response to each of them.
+	fn run_connection(server: StratumServer, lines: &[&str]) -> (Vec<serde_json::Value>, Vec<serde_json::Value>, usize) {
+		let limiter = ClientLimiter::new(LimitsConfig::default());
+		let slot = ClientLimiter::try_accept(&limiter, None).unwrap();
+		let rc = Rc::new(RefCell::new(server));
+		let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+		current_thread::block_on_all(future::lazy(move || {
+			let (ours, theirs) = tokio_uds::UnixStream::pair().unwrap();
+			StratumServer::new_connection(rc.clone(), theirs, slot);
+			let (tx, rx) = ours.framed(LinesCodec::new()).split();
+			let msgs_rx = rx.map(|line| serde_json::from_str::<serde_json::Value>(&line).unwrap());
+			future::loop_fn((tx, msgs_rx, lines.into_iter(), Vec::new(), Vec::new(), 0), |(tx, rx, mut lines, mut responses, mut notifications, awaiting)| {
+				if awaiting > 0 {
+					return future::Either::A(rx.into_future().map_err(|(e, _)| e).map(move |(msg, rx)| {
+						let msg = msg.unwrap();
+						if msg["id"].is_null() {
+							notifications.push(msg);
+							future::Loop::Continue((tx, rx, lines, responses, notifications, awaiting))
+						} else {
+							responses.push(msg);
+							future::Loop::Continue((tx, rx, lines, responses, notifications, awaiting - 1))
+						}
+					}));
+				}
+				let line = match lines.next() {
+					Some(line) => line,
+					None => return future::Either::B(future::Either::A(future::ok(future::Loop::Break((tx, responses, notifications))))),
+				};
+				let awaiting = line.lines().count();
+				future::Either::B(future::Either::B(tx.send(line).map(move |tx| {
+					future::Loop::Continue((tx, rx, lines, responses, notifications, awaiting))
+				})))
+			}).map(move |(tx, responses, notifications)| {
+				let clients = rc.borrow().clients.len();
+				drop(tx);
+				(responses, notifications, clients)
+			})
+		})).unwrap()
+	}
+
+	#[test]
+	fn test_connection_errors() {
+		let start = Instant::now();
+		let (server, _) = test_server(test_template_with_target(), VardiffConfig::default(), start);
+		let (responses, _, clients) = run_connection(server, &[
+			r#"{"id":1,"method":"mining.submit","params":["w","1","0000000000000000","59682f00","00000001"]}"#,
+			r#"{"id":2,"method":"mining.subscribe","params":[]}"#,
+			r#"{"id":3,"method":"mining.submit","params":["w","1","0000000000000000","59682f00","00000001"]}"#,
+			r#"{"id":4,"method":"mining.authorize","params":["w","x"]}"#,
+			r#"{"id":5,"method":"mining.submit","params":["w","7","0000000000000000","59682f00","00000001"]}"#,
+			r#"{"id":6,"method":"mining.submit","params":["w","1","00","59682f00","00000001"]}"#,
+			// Accepted as it meets the all-0xff target the client had before its first difficulty
+			r#"{"id":7,"method":"mining.submit","params":["w","1","0000000000000000","59682f00","00000001"]}"#,
+			r#"{"id":8,"method":"mining.submit","params":["w","1","0000000000000000","59682f00","00000001"]}"#,
+			r#"{"id":9,"method":"mining.suggest_difficulty","params":[2]}"#,
+			r#"{"id":10,"method":"mining.submit","params":["w","1","0000000000000000","59682f00","00000002"]}"#,
+			r#"{"id":11,"method":"mining.submit","params":["w",1]}"#,
+			r#"{"id":12,"method":"mining.bogus","params":[]}"#,
+		]);
+		assert_eq!(responses.len(), 12);
+		for (idx, response) in responses.iter().enumerate() {
+			assert_eq!(response["id"], idx + 1);
+		}
+		assert_eq!(responses[0]["error"], json!([25, "Not subscribed", null]));
+		assert_eq!(responses[1]["error"], serde_json::Value::Null);
+		assert_eq!(responses[1]["result"][2], 8);
+		assert_eq!(responses[2]["error"], json!([24, "Unauthorized worker", null]));
+		assert_eq!(responses[3]["result"], true);
+		assert_eq!(responses[4]["error"], json!([21, "Job not found", null]));
+		assert_eq!(responses[5]["error"], json!([20, "Incorrect size of extranonce2", null]));
+		assert_eq!(responses[6]["result"], true);
+		assert_eq!(responses[7]["error"], json!([22, "Duplicate share", null]));
+		assert_eq!(responses[8]["result"], true);
+		assert_eq!(responses[9]["error"], json!([23, "Low difficulty share", null]));
+		assert_eq!(responses[10]["error"], json!([20, "Malformed submit", null]));
+		assert_eq!(responses[11]["error"], json!([20, "Unknown method", null]));
+		for response in responses.iter().filter(|response| !response["error"].is_null()) {
+			assert_eq!(response["result"], serde_json::Value::Null);
+		}
+		// None of which closed the connection
+		assert_eq!(clients, 3);
+	}
+
+	#[test]
+	fn test_overlong_line() {
+		let (server, _) = test_server(test_template(), VardiffConfig::default(), Instant::now());
+		let limiter = ClientLimiter::new(LimitsConfig {
+			ban_after_violations: 1,
+			..Default::default()
+		});
+		let ip: IpAddr = "10.0.0.1".parse().unwrap();
+		let slot = ClientLimiter::try_accept(&limiter, Some(ip)).unwrap();
+		let rc = Rc::new(RefCell::new(server));
+		let rc_conn = rc.clone();
+		let response = current_thread::block_on_all(future::lazy(move || {
+			let (ours, theirs) = tokio_uds::UnixStream::pair().unwrap();
+			StratumServer::new_connection(rc_conn, theirs, slot);
+			let (tx, rx) = ours.framed(LinesCodec::new()).split();
+			let line = format!(r#"{{"id":1,"method":"mining.subscribe","params":["{}"]}}"#, "a".repeat(MAX_LINE_LENGTH));
+			tx.send(line).and_then(|tx| {
+				rx.into_future().then(move |res| {
+					drop(tx);
+					future::ok::<_, io::Error>(res.ok().and_then(|(msg, _)| msg))
+				})
+			})
+		})).unwrap();
+		// The client is dropped without a response and its IP banned for the violation
+		assert!(response.is_none());
+		assert_eq!(rc.borrow().clients.len(), 2);
+		assert_eq!(limiter.lock().unwrap().list_bans().len(), 1);
+	}
+
+	#[test]
+	fn test_get_transactions_pending() {
+		let (mut server, _) = test_server(test_template(), VardiffConfig::default(), Instant::now());
+		let (txn_tx, txn_rx) = sync_oneshot::channel();
+		txn_tx.send(Arc::new(Vec::new())).unwrap();
+		server.jobs.get_mut(&1).unwrap().work.transactions = txn_rx.shared();
+		let (responses, _, clients) = run_connection(server, &[
+			// Both arrive before we've answered the first
+			concat!(r#"{"id":1,"method":"mining.get_transactions","params":["1"]}"#, "\n",
+				r#"{"id":2,"method":"mining.get_transactions","params":["1"]}"#),
+			r#"{"id":3,"method":"mining.get_transactions","params":[]}"#,
+		]);
+		assert_eq!(responses.len(), 3);
+		assert_eq!(responses[0]["id"], 2);
+		assert_eq!(responses[0]["error"], json!([20, "Already waiting on transactions", null]));
+		assert_eq!(responses[1]["id"], 1);
+		assert_eq!(responses[1]["result"], json!([]));
+		// Once answered, the client may ask again
+		assert_eq!(responses[2]["id"], 3);
+		assert_eq!(responses[2]["result"], json!([]));
+		assert_eq!(clients, 3);
+	}
+
+	#[test]
+	fn test_configure() {
+		let (server, _) = test_server(test_template_with_target(), VardiffConfig::default(), Instant::now());
+		let job_difficulty = utils::target_to_diff(&server.jobs[&1].work.template.target);
+		let (responses, notifications, clients) = run_connection(server, &[
+			r#"{"id":1,"method":"mining.configure","params":[["version-rolling","minimum-difficulty","subscribe-extranonce","foo"],{"version-rolling.mask":"ffffffff","minimum-difficulty.value":4}]}"#,
+			r#"{"id":2,"method":"mining.configure","params":[["version-rolling"],{"version-rolling.mask":"nothex"}]}"#,
+			r#"{"id":3,"method":"mining.configure","params":[["version-rolling"],["version-rolling.mask"]]}"#,
+			r#"{"id":4,"method":"mining.configure","params":[["minimum-difficulty"],{"minimum-difficulty.value":-1}]}"#,
+			r#"{"id":5,"method":"mining.configure","params":[["version-rolling"],{"version-rolling.mask":"00ffe000"}]}"#,
+			r#"{"id":6,"method":"mining.subscribe","params":[]}"#,
+			r#"{"id":7,"method":"mining.authorize","params":["w","x"]}"#,
+			r#"{"id":8,"method":"mining.submit","params":["w","1","0000000000000000","59682f00","00000001","1f000000"]}"#,
+			r#"{"id":9,"method":"mining.submit","params":["w","1","0000000000000000","59682f00","00000001","00002000"]}"#,
+		]);
+		assert_eq!(responses.len(), 9);
+		// We only let the client roll the job's version bits, whatever mask it asks for
+		assert_eq!(responses[0]["result"], json!({
+			"version-rolling": true,
+			"version-rolling.mask": "1fffe000",
+			"minimum-difficulty": true,
+			"subscribe-extranonce": true,
+			"foo": false,
+		}));
+		for response in responses[1..4].iter() {
+			assert_eq!(response["error"], json!([20, "Malformed configure", null]));
+		}
+		assert_eq!(responses[4]["result"], json!({"version-rolling": true, "version-rolling.mask": "00ffe000"}));
+		assert_eq!(responses[5]["error"], serde_json::Value::Null);
+		assert_eq!(responses[6]["result"], true);
+		assert_eq!(responses[7]["error"], json!([20, "Version bits outside of negotiated mask", null]));
+		assert_eq!(responses[8]["result"], true);
+
+		// The client starts at the job's difficulty, well above the minimum it asked for
+		assert_eq!(notifications[0]["method"], "mining.set_difficulty");
+		assert_eq!(notifications[0]["params"][0], job_difficulty);
+		assert_eq!(clients, 3);
+	}
+
+	#[test]
+	fn test_fast_client() {
+		let vardiff_config = VardiffConfig {
+			max_difficulty: 65536.0,
+			..Default::default()
+		};
+		let (server, _) = test_server(test_template_with_target(), vardiff_config, Instant::now());
+		let mut lines = vec![
+			r#"{"id":1,"method":"mining.subscribe","params":[]}"#.to_string(),
+			r#"{"id":2,"method":"mining.authorize","params":["w","x"]}"#.to_string(),
+		];
+		// A burst of shares, which all meet the all-0xff target the client had before its first
+		// difficulty, as a fast miner left at a low starting difficulty would send
+		for nonce in 0..50 {
+			lines.push(format!(r#"{{"id":{},"method":"mining.submit","params":["w","1","0000000000000000","59682f00","{:08x}"]}}"#, nonce + 3, nonce));
+		}
+		let lines: Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+		let (responses, notifications, clients) = run_connection(server, &lines);
+		assert_eq!(responses.len(), 52);
+		for response in responses[1..].iter() {
+			assert_eq!(response["result"], true);
+		}
+		// The client starts at max_difficulty rather than min_difficulty, and as the job doesn't
+		// allow any more it stays there despite the flood
+		let difficulties: Vec<_> = notifications.iter().filter(|msg| msg["method"] == "mining.set_difficulty").collect();
+		assert_eq!(difficulties.len(), 1);
+		assert_eq!(difficulties[0]["params"][0], 65536.0);
+		// ...and stays connected under the default limits
+		assert_eq!(clients, 3);
+	}
+
+	#[test]
+	fn test_full_send_queue() {
+		let (server, mut receivers) = test_server(test_template(), VardiffConfig::default(), Instant::now());
+		let job_target = server.jobs[&1].work.template.target;
+		let mut client = server.clients[0].borrow_mut();
+		// A full queue parks the current task, so we need one
+		executor::spawn(future::lazy(|| -> Result<(), ()> {
+			for _ in 0..CLIENT_SEND_QUEUE_LEN {
+				assert_eq!(client.send("{}".to_string()), Ok(()));
+			}
+			// The message didn't make it into the queue, so we don't consider it sent
+			assert_eq!(client.update_difficulty(&job_target), Err(()));
+			assert_eq!(client.sent_difficulty, None);
+			Ok(())
+		})).wait_future().unwrap();
+		assert_eq!(sent_messages(&mut receivers[0]).len(), CLIENT_SEND_QUEUE_LEN);
+		assert_eq!(client.update_difficulty(&job_target), Ok(true));
+	}
+
+	#[test]
+	fn test_job_eviction() {
+		let start = Instant::now();
+		let (mut server, _) = test_server(test_template(), VardiffConfig::default(), start);
+		// Nobody needs to hear about the jobs, we only care which ones are kept
+		server.clients.clear();
+		server.job_retention = JobRetention {
+			max_jobs: 3,
+			max_age: Duration::from_secs(60),
+		};
+		let (solutions, _solutions_rx) = sync_mpsc::unbounded();
+		let job = |template_id: u64, prevblock: u8| {
+			let mut template = test_template();
+			template.template_id = template_id;
+			template.header_prevblock = [prevblock; 32];
+			WorkInfo { template: Arc::new(template), solutions: solutions.clone(), transactions: no_transactions() }
+		};
+
+		// Job 1 is evicted as we go over max_jobs
+		server.announce_job(job(2, 0x11), false, start + Duration::from_secs(10));
+		server.announce_job(job(3, 0x11), false, start + Duration::from_secs(20));
+		server.announce_job(job(4, 0x11), false, start + Duration::from_secs(30));
+		assert_eq!(server.jobs.keys().cloned().collect::<Vec<_>>(), vec![2, 3, 4]);
+
+		// Job 2 is evicted once it's max_age old, job 3 isn't yet
+		server.announce_job(job(5, 0x11), false, start + Duration::from_secs(75));
+		assert_eq!(server.jobs.keys().cloned().collect::<Vec<_>>(), vec![3, 4, 5]);
+
+		// The latest job is kept however old it is
+		server.evict_jobs(start + Duration::from_secs(1000));
+		assert_eq!(server.jobs.keys().cloned().collect::<Vec<_>>(), vec![5]);
+
+		// A new block invalidates every job built on the previous one
+		server.announce_job(job(6, 0x11), false, start + Duration::from_secs(1001));
+		server.announce_job(job(7, 0x22), true, start + Duration::from_secs(1002));
+		assert_eq!(server.jobs.keys().cloned().collect::<Vec<_>>(), vec![7]);
+
+		// Shares on an evicted job are stale, while ones on a job we never had are just unknown
+		let (responses, _, _) = run_connection(server, &[
+			r#"{"id":1,"method":"mining.subscribe","params":[]}"#,
+			r#"{"id":2,"method":"mining.authorize","params":["w","x"]}"#,
+			r#"{"id":3,"method":"mining.submit","params":["w","6","0000000000000000","59682f00","00000001"]}"#,
+			r#"{"id":4,"method":"mining.submit","params":["w","8","0000000000000000","59682f00","00000001"]}"#,
+		]);
+		assert_eq!(responses[2]["error"], json!([21, "Job not found (=stale)", null]));
+		assert_eq!(responses[3]["error"], json!([21, "Job not found", null]));
+	}
+
+	#[test]
+	fn test_renotify_idle_clients() {
+		let start = Instant::now();
+		let (mut server, mut receivers) = test_server(test_template(), VardiffConfig::default(), start);
+
+		let idle = Duration::from_secs(30);
+		server.renotify_idle_clients(idle, start + Duration::from_secs(29));
+		assert!(sent_messages(&mut receivers[0]).is_empty());
+
+		server.renotify_idle_clients(idle, start + Duration::from_secs(31));
+		let msgs = sent_messages(&mut receivers[0]);
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(msgs[0]["method"], "mining.notify");
+		let params = msgs[0]["params"].as_array().unwrap();
+		assert_eq!(params[0], "1");
+		// ntime moved forward by the 31 seconds since we got the job, without clearing jobs
+		assert_eq!(params[7], format!("{:08x}", 1_500_000_000 + 31));
+		assert_eq!(params[8], false);
+		assert!(sent_messages(&mut receivers[1]).is_empty());
+
+		// The idle interval restarts from the last notify
+		server.renotify_idle_clients(idle, start + Duration::from_secs(60));
+		assert!(sent_messages(&mut receivers[0]).is_empty());
+		server.renotify_idle_clients(idle, start + Duration::from_secs(61));
+		assert_eq!(sent_messages(&mut receivers[0]).len(), 1);
+		assert!(sent_messages(&mut receivers[1]).is_empty());
+	}
+
+	#[test]
+	fn test_retarget_clients() {
+		let start = Instant::now();
+		let vardiff_config = VardiffConfig {
+			min_difficulty: 64.0,
+			..Default::default()
+		};
+		let (mut server, mut receivers) = test_server(test_template_with_target(), vardiff_config, start);
+		{
+			// Lowered from the job's difficulty it starts at
+			let mut client = server.clients[0].borrow_mut();
+			client.vardiff.suggest(&vardiff_config, 64.0, start);
+			assert_eq!(client.update_difficulty(&server.jobs[&1].work.template.target), Ok(true));
+		}
+		let msgs = sent_messages(&mut receivers[0]);
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(msgs[0]["method"], "mining.set_difficulty");
+		assert_eq!(msgs[0]["params"][0], 64.0);
+
+		// Shares meeting the client's target are accepted, but ones which only meet the target it had
+		// before its last difficulty change are as well
+		let mut hash = [0; 32];
+		hash[27] = 0x03;
+		assert!(server.clients[0].borrow().does_hash_meet_share_target(&hash[..]));
+		hash[27] = 0x04;
+		assert!(server.clients[0].borrow().does_hash_meet_share_target(&hash[..]));
+		let share_target = server.clients[0].borrow().share_target;
+		server.clients[0].borrow_mut().prev_share_target = share_target;
+		assert!(!server.clients[0].borrow().does_hash_meet_share_target(&hash[..]));
+
+		// Nothing happens until the retarget window ends
+		server.retarget_clients(start + Duration::from_secs(59));
+		assert!(sent_messages(&mut receivers[0]).is_empty());
+
+		// A client which found 12 shares in a window where we expected 6 doubles its difficulty, and is
+		// sent the latest job
+		for i in 0..12 {
+			assert!(server.clients[0].borrow_mut().vardiff.share_accepted(&vardiff_config, 1048576.0, start + Duration::from_secs(i)).is_none());
+		}
+		server.retarget_clients(start + Duration::from_secs(60));
+		let msgs = sent_messages(&mut receivers[0]);
+		assert_eq!(msgs.len(), 2);
+		assert_eq!(msgs[0]["method"], "mining.set_difficulty");
+		assert_eq!(msgs[0]["params"][0], 128.0);
+		assert_eq!(msgs[1]["method"], "mining.notify");
+		assert_eq!(msgs[1]["params"][8], false);
+		assert_eq!(server.clients[0].borrow().share_target, utils::diff_to_target(128.0));
+		assert!(sent_messages(&mut receivers[1]).is_empty());
+	}
+
+	#[test]
+	fn test_extranonce2_size_change() {
+		let start = Instant::now();
+		let (mut server, mut receivers) = test_server(test_template(), VardiffConfig::default(), start);
+		// Add a second subscribed client, which hasn't sent mining.extranonce.subscribe
+		server.clients[1].borrow_mut().subscribed = true;
+		server.clients[0].borrow_mut().extranonce_subscribed = true;
+		let (solutions, _solutions_rx) = sync_mpsc::unbounded();
+
+		// A job which leaves room for the full extranonce2 doesn't change anything
+		let mut template = test_template();
+		template.template_id = 2;
+		template.coinbase_prefix = vec![0; 42];
+		template.coinbase_postfix = vec![0; 42];
+		server.announce_job(WorkInfo { template: Arc::new(template.clone()), solutions: solutions.clone(), transactions: no_transactions() }, false, start);
+		for receiver in receivers.iter_mut() {
+			let msgs = sent_messages(receiver);
+			assert_eq!(msgs.len(), 2);
+			assert_eq!(msgs[0]["method"], "mining.set_difficulty");
+			assert_eq!(msgs[1]["method"], "mining.notify");
+		}
+
+		// A longer coinbase postfix leaves only 4 bytes of the 100 byte scriptSig limit
+		template.template_id = 3;
+		template.coinbase_postfix = vec![0; 46];
+		server.announce_job(WorkInfo { template: Arc::new(template.clone()), solutions: solutions.clone(), transactions: no_transactions() }, false, start);
+		let msgs = sent_messages(&mut receivers[0]);
+		assert_eq!(msgs.len(), 2);
+		assert_eq!(msgs[0]["method"], "mining.set_extranonce");
+		assert_eq!(msgs[0]["params"], json!(["0000000000000000", 4]));
+		assert_eq!(msgs[1]["method"], "mining.notify");
+		// The scriptSig is the prefix, 8 bytes of extranonce1, 4 of extranonce2 and the postfix
+		assert!(msgs[1]["params"][2].as_str().unwrap().contains("ffffffff64"));
+		assert_eq!(server.clients[0].borrow().extranonce2_size, 4);
+
+		// The client which can't take mining.set_extranonce is asked to reconnect instead, and isn't
+		// sent jobs it can't work on in the mean time
+		let msgs = sent_messages(&mut receivers[1]);
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(msgs[0]["method"], "client.reconnect");
+		assert_eq!(server.clients[1].borrow().extranonce2_size, EXTRANONCE2_SIZE);
+		server.renotify_idle_clients(Duration::from_secs(30), start + Duration::from_secs(31));
+		assert_eq!(sent_messages(&mut receivers[0]).len(), 1);
+		assert!(sent_messages(&mut receivers[1]).is_empty());
+
+		// Further jobs at the new size don't ask it to reconnect again
+		template.template_id = 4;
+		server.announce_job(WorkInfo { template: Arc::new(template.clone()), solutions: solutions.clone(), transactions: no_transactions() }, false, start + Duration::from_secs(10));
+		assert_eq!(sent_messages(&mut receivers[0]).len(), 1);
+		assert!(sent_messages(&mut receivers[1]).is_empty());
+
+		// If it still hasn't reconnected once the grace period is up, its connection is closed
+		let (kill_tx, kill_rx) = oneshot::channel();
+		server.clients[1].borrow_mut().kill_conn = Some(kill_tx);
+		let mut kill_rx = executor::spawn(kill_rx);
+		let notify = Arc::new(NoopNotify);
+		server.close_unreconnected_clients(start + Duration::from_secs(RECONNECT_GRACE_PERIOD_SECS - 1));
+		assert_eq!(kill_rx.poll_future_notify(&notify, 0), Ok(Async::NotReady));
+		server.close_unreconnected_clients(start + Duration::from_secs(RECONNECT_GRACE_PERIOD_SECS));
+		assert_eq!(kill_rx.poll_future_notify(&notify, 0), Ok(Async::Ready(())));
+	}
+
+	#[test]
+	fn test_difficulty_hints() {
+		assert_eq!(split_password_difficulty("x"), ("x".to_string(), None));
+		assert_eq!(split_password_difficulty("d=1024"), ("".to_string(), Some(1024.0)));
+		assert_eq!(split_password_difficulty("secret,d=16.5"), ("secret".to_string(), Some(16.5)));
+		assert_eq!(split_password_difficulty("x; d=512"), ("x".to_string(), Some(512.0)));
+		assert_eq!(split_password_difficulty("a;d=2,b"), ("a,b".to_string(), Some(2.0)));
+		// Passwords without a valid hint are left exactly as they were
+		assert_eq!(split_password_difficulty("a;b"), ("a;b".to_string(), None));
+		assert_eq!(split_password_difficulty("d=nope,x"), ("d=nope,x".to_string(), None));
+		assert_eq!(split_password_difficulty("d=-5"), ("d=-5".to_string(), None));
+		assert_eq!(split_password_difficulty("x,d=abc;d=8"), ("x,d=abc".to_string(), Some(8.0)));
+
+		assert_eq!(target_hex_to_diff("00000000ffff0000000000000000000000000000000000000000000000000000").unwrap(), 1.0);
+		assert_eq!(target_hex_to_diff("0000000000ffff00000000000000000000000000000000000000000000000000").unwrap(), 256.0);
+		assert!(target_hex_to_diff("00000000ffff").is_err());
+
+		let start = Instant::now();
+		let vardiff_config = VardiffConfig {
+			min_difficulty: 64.0,
+			max_difficulty: 4096.0,
+			..Default::default()
+		};
+		let (server, mut receivers) = test_server(test_template_with_target(), vardiff_config, start);
+
+		// A client which hasn't subscribed just starts at the suggestion when it does
+		assert!(server.apply_suggested_difficulty(&mut server.clients[1].borrow_mut(), 1000.0, start).is_ok());
+		assert!(sent_messages(&mut receivers[1]).is_empty());
+		assert_eq!(server.clients[1].borrow().vardiff.get_difficulty(), 1000.0);
+
+		// A subscribed one is moved over right away, within our bounds
+		assert!(server.apply_suggested_difficulty(&mut server.clients[0].borrow_mut(), 1e6, start).is_ok());
+		let msgs = sent_messages(&mut receivers[0]);
+		assert_eq!(msgs.len(), 2);
+		assert_eq!(msgs[0]["method"], "mining.set_difficulty");
+		assert_eq!(msgs[0]["params"][0], 4096.0);
+		assert_eq!(msgs[1]["method"], "mining.notify");
+		assert_eq!(msgs[1]["params"][8], false);
+	}
+
+	#[test]
+	fn test_transactions_to_json() {
+		let tx = Transaction {
+			version: 1,
+			input: vec!(TxIn {
+				prev_hash: Sha256dHash::from(&[0x22; 32][..]),
+				prev_index: 1,
+				script_sig: Script::new(),
+				sequence: 0xffffffff,
+			}),
+			output: vec!(TxOut {
+				value: 5000,
+				script_pubkey: Script::new(),
+			}),
+			witness: vec!(),
+			lock_time: 0,
+		};
+		let tx_hex = "0100000001222222222222222222222222222222222222222222222222222222222222222201000000\
+			00ffffffff0188130000000000000000000000";
+		assert_eq!(transactions_to_json(&[tx.clone()], TransactionsFormat::Hex), json!([tx_hex]));
+
+		// Txids are the sha256d of the serialization, byte-reversed as usual for display
+		let tx_bytes = utils::hex_to_vec(tx_hex).unwrap();
+		let mut hash = [0; 32];
+		let mut sha = Sha256::new();
+		sha.input(&tx_bytes);
+		sha.result(&mut hash);
+		sha.reset();
+		sha.input(&hash);
+		sha.result(&mut hash);
+		hash.reverse();
+		assert_eq!(transactions_to_json(&[tx], TransactionsFormat::Txids), json!([utils::bytes_to_hex(&hash)]));
+		assert_eq!(transactions_to_json(&[], TransactionsFormat::Hex), json!([]));
+	}
+
+	#[test]
+	fn test_version_rolling() {
+		let start = Instant::now();
+		let mut template = test_template();
+		assert_eq!(job_version_mask(&template), 0x1fffe000);
+		let (mut server, mut receivers) = test_server(template.clone(), VardiffConfig::default(), start);
+		{
+			// As if mining.configure asked for the common ASIC mask
+			let mut client = server.clients[0].borrow_mut();
+			client.version_rolling_mask = Some(0x1fffe000);
+			client.version_mask = 0x1fffe000;
+		}
+		let (solutions, _solutions_rx) = sync_mpsc::unbounded();
+
+		// A template signalling on bit 16 takes that bit away, and clients are told before the job
+		template.template_id = 2;
+		template.header_version = 0x20010000;
+		assert_eq!(job_version_mask(&template), 0x1ffee000);
+		server.announce_job(WorkInfo { template: Arc::new(template.clone()), solutions: solutions.clone(), transactions: no_transactions() }, false, start);
+		let msgs = sent_messages(&mut receivers[0]);
+		assert_eq!(msgs.len(), 3);
+		assert_eq!(msgs[0]["method"], "mining.set_version_mask");
+		assert_eq!(msgs[0]["params"][0], "1ffee000");
+		assert_eq!(msgs[1]["method"], "mining.set_difficulty");
+		assert_eq!(msgs[2]["method"], "mining.notify");
+		assert_eq!(server.jobs[&2].version_mask, 0x1ffee000);
+
+		// No change, no message
+		template.template_id = 3;
+		server.announce_job(WorkInfo { template: Arc::new(template.clone()), solutions: solutions.clone(), transactions: no_transactions() }, false, start);
+		let msgs = sent_messages(&mut receivers[0]);
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(msgs[0]["method"], "mining.notify");
+
+		// Clients which never negotiated version rolling are never told about masks
+		assert!(server.clients[1].borrow_mut().update_version_mask(0x1fffe000).is_ok());
+		assert!(sent_messages(&mut receivers[1]).is_empty());
+		assert_eq!(server.clients[1].borrow().version_mask, 0);
+	}
+}
@@ -0,0 +1,204 @@
+// TLS for our listeners and upstream connections, optionally pinning upstreams to a certificate or
+// public key instead of relying on CA validation.
+
+use socks5;
+use utils;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use futures::future;
+use futures::Future;
+
+use native_tls;
+
+use tokio_io::{AsyncRead,AsyncWrite};
+
+use tokio_tls;
+use tokio_tls::TlsStream;
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+#[derive(Clone)]
+pub enum TlsPin {
+	/// sha256 of the DER-encoded leaf certificate
+	Cert([u8; 32]),
+	/// sha256 of the DER-encoded SubjectPublicKeyInfo of the leaf certificate, which survives
+	/// certificate renewal as long as the key stays the same
+	Spki([u8; 32]),
+}
+
+impl TlsPin {
+	/// Parses cert:hex or spki:hex
+	pub fn parse(pin: &str) -> Option<TlsPin> {
+		let (kind, hex) = match pin.find(':') {
+			Some(idx) => (&pin[..idx], &pin[idx + 1..]),
+			None => return None,
+		};
+		let hash = match utils::hex_to_vec(hex) {
+			Some(ref bytes) if bytes.len() == 32 => {
+				let mut hash = [0; 32];
+				hash.copy_from_slice(&bytes[..]);
+				hash
+			},
+			_ => return None,
+		};
+		match kind {
+			"cert" => Some(TlsPin::Cert(hash)),
+			"spki" => Some(TlsPin::Spki(hash)),
+			_ => None,
+		}
+	}
+
+	fn matches(&self, cert_der: &[u8]) -> bool {
+		match self {
+			&TlsPin::Cert(ref hash) => sha256(cert_der) == *hash,
+			&TlsPin::Spki(ref hash) => match spki_from_cert(cert_der) {
+				Some(spki) => sha256(spki) == *hash,
+				None => false,
+			},
+		}
+	}
+}
+
+#[derive(Clone)]
+pub struct UpstreamTls {
+	/// If set, CA and hostname validation are replaced with a check against this pin, as pools
+	/// commonly use self-signed certificates
+	pub pin: Option<TlsPin>,
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+	let mut hasher = Sha256::new();
+	hasher.input(data);
+	let mut res = [0; 32];
+	hasher.result(&mut res);
+	res
+}
+
+/// Reads the DER TLV at the start of data, returning its tag, total length and header length
+fn der_read(data: &[u8]) -> Option<(u8, usize, usize)> {
+	if data.len() < 2 { return None; }
+	let (len, header_len) = if (data[1] & 0x80) == 0 {
+		(data[1] as usize, 2)
+	} else {
+		let len_bytes = (data[1] & 0x7f) as usize;
+		if len_bytes == 0 || len_bytes > 4 || data.len() < 2 + len_bytes { return None; }
+		let mut len = 0;
+		for b in data[2..2 + len_bytes].iter() {
+			len = (len << 8) | (*b as usize);
+		}
+		(len, 2 + len_bytes)
+	};
+	if data.len() < header_len + len { return None; }
+	Some((data[0], header_len + len, header_len))
+}
+
+/// Finds the DER-encoded SubjectPublicKeyInfo in a DER-encoded X.509 certificate
+fn spki_from_cert(cert: &[u8]) -> Option<&[u8]> {
+	let (tag, _, header_len) = der_read(cert)?;
+	if tag != 0x30 { return None; }
+	let cert = &cert[header_len..];
+
+	let (tag, tbs_len, header_len) = der_read(cert)?;
+	if tag != 0x30 { return None; }
+	let mut tbs = &cert[header_len..tbs_len];
+
+	// Skip the optional explicit version, then serialNumber, signature, issuer, validity and subject
+	if !tbs.is_empty() && tbs[0] == 0xa0 {
+		let (_, len, _) = der_read(tbs)?;
+		tbs = &tbs[len..];
+	}
+	for _ in 0..5 {
+		let (_, len, _) = der_read(tbs)?;
+		tbs = &tbs[len..];
+	}
+
+	let (tag, len, _) = der_read(tbs)?;
+	if tag != 0x30 { return None; }
+	Some(&tbs[..len])
+}
+
+/// Builds an acceptor from a PEM certificate chain and a PEM PKCS#8 private key
+pub fn load_acceptor(cert_path: &str, key_path: &str) -> Result<tokio_tls::TlsAcceptor, String> {
+	let mut cert = Vec::new();
+	let mut key = Vec::new();
+	match File::open(cert_path).and_then(|mut f| f.read_to_end(&mut cert)) {
+		Ok(_) => {},
+		Err(e) => return Err(format!("Failed to read {}: {}", cert_path, e)),
+	}
+	match File::open(key_path).and_then(|mut f| f.read_to_end(&mut key)) {
+		Ok(_) => {},
+		Err(e) => return Err(format!("Failed to read {}: {}", key_path, e)),
+	}
+	let identity = match native_tls::Identity::from_pkcs8(&cert, &key) {
+		Ok(identity) => identity,
+		Err(e) => return Err(format!("Failed to load TLS certificate/key: {}", e)),
+	};
+	match native_tls::TlsAcceptor::new(identity) {
+		Ok(acceptor) => Ok(tokio_tls::TlsAcceptor::from(acceptor)),
+		Err(e) => Err(format!("Failed to set up TLS: {}", e)),
+	}
+}
+
+/// Starts a TLS session to host_port (of the form host:port) over stream
+pub fn connect<S: AsyncRead + AsyncWrite + 'static>(stream: S, host_port: &str, config: &UpstreamTls) -> Box<Future<Item=TlsStream<S>, Error=io::Error>> {
+	let host = match socks5::split_host_port(host_port) {
+		Some((host, _)) => host,
+		None => return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, utils::HandleError))),
+	};
+
+	let mut builder = native_tls::TlsConnector::builder();
+	if config.pin.is_some() {
+		builder.danger_accept_invalid_certs(true);
+		builder.danger_accept_invalid_hostnames(true);
+	}
+	let connector = match builder.build() {
+		Ok(connector) => tokio_tls::TlsConnector::from(connector),
+		Err(e) => return Box::new(future::err(io::Error::new(io::ErrorKind::Other, e))),
+	};
+
+	let pin = config.pin.clone();
+	Box::new(connector.connect(&host, stream).map_err(|e| {
+		println!("TLS handshake failed: {}", e);
+		io::Error::new(io::ErrorKind::Other, e)
+	}).and_then(move |stream| {
+		if let Some(pin) = pin {
+			let cert_der = match stream.get_ref().peer_certificate() {
+				Ok(Some(cert)) => cert.to_der().ok(),
+				_ => None,
+			};
+			match cert_der {
+				Some(ref der) if pin.matches(der) => {},
+				_ => {
+					println!("Upstream TLS certificate for {} did not match our pin", host);
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
+			}
+		}
+		Ok(stream)
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use tls;
+	use utils;
+
+	// Self-signed P-256 certificate for pool.example.com
+	const TEST_CERT: &str = "3082018c30820131a00302010202142853f2ea113ccd059a15b0b1a5224186262156f5300a06082a8648ce3d040302301b3119301706035504030c10706f6f6c2e6578616d706c652e636f6d301e170d3236313031383132353135305a170d3336313031353132353135305a301b3119301706035504030c10706f6f6c2e6578616d706c652e636f6d3059301306072a8648ce3d020106082a8648ce3d030107034200049a9d89054bb637ce29cc5b91da28f19d22557611943421e505eecc75344eaa486a50d6cc4ff210eec96d466f82dd4981237443e7cffe1c627ff59cfc33d333cca3533051301d0603551d0e04160414e4cdb08b7281478de75859f933e9f0670e44fa42301f0603551d23041830168014e4cdb08b7281478de75859f933e9f0670e44fa42300f0603551d130101ff040530030101ff300a06082a8648ce3d0403020349003046022100c896d982cd500265e46797077db261f57d8e77ec433983edb7051486e7e903e1022100f290981d30f59dfadde6f683aee153d4036c83c2676b34d6d35a5965a00623cc";
+
+	#[test]
+	fn test_pins() {
+		let cert = utils::hex_to_vec(TEST_CERT).unwrap();
+		// Hashes as computed by openssl x509 -outform der | sha256sum (and -pubkey | openssl pkey -pubin -outform der)
+		assert!(tls::TlsPin::parse("cert:0d118a9381534770eb591e3e85e36b35cbaee352730a5cd72e08eb63fc9af4a3").unwrap().matches(&cert));
+		assert!(tls::TlsPin::parse("spki:8dec48d101ec54e701a43e22dcd5d7f139ff6b0c4cecdf4762ba5a3eb7a71e68").unwrap().matches(&cert));
+		assert!(!tls::TlsPin::parse("spki:0d118a9381534770eb591e3e85e36b35cbaee352730a5cd72e08eb63fc9af4a3").unwrap().matches(&cert));
+		assert!(!tls::TlsPin::parse("spki:8dec48d101ec54e701a43e22dcd5d7f139ff6b0c4cecdf4762ba5a3eb7a71e68").unwrap().matches(&cert[..cert.len() - 1]));
+		assert!(tls::TlsPin::parse("sha1:8dec48d101ec54e701a43e22dcd5d7f139ff6b0c4cecdf4762ba5a3eb7a71e68").is_none());
+		assert!(tls::TlsPin::parse("cert:8dec48").is_none());
+	}
+}
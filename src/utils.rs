@@ -25,19 +25,114 @@ pub fn max_le(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
 	a
 }
 
-pub fn target_to_diff_lb(target: &[u8; 32]) -> f64 {
-	// We use a shitty approximation for a lower-bound on difficulty by simply calculating the
-	// number of leading 0 bits in the target and applying a fudge factor...this will result in
-	// up to 3/4 of shares failing, but that's OK.
+/// Gets the stratum difficulty of a target, ie the difficulty 1 target (0xffff << 208) divided by
+/// target, to within f64 precision. A zero target gives infinity.
+pub fn target_to_diff(target: &[u8; 32]) -> f64 {
+	let top_byte = match target.iter().rposition(|b| *b != 0) {
+		Some(idx) => idx,
+		None => return std::f64::INFINITY,
+	};
+	// Take the top 16 bytes, which is far more precision than an f64 can hold, and scale back up
+	let low_byte = if top_byte >= 15 { top_byte - 15 } else { 0 };
+	let mut top_bits: u128 = 0;
+	for i in (low_byte..top_byte + 1).rev() {
+		top_bits = (top_bits << 8) | target[i] as u128;
+	}
+	let target_f64 = top_bits as f64 * 2f64.powi(8 * low_byte as i32);
+	(0xffff as f64) * 2f64.powi(208) / target_f64
+}
+
+/// Gets the target for a stratum difficulty, ie the difficulty 1 target (0xffff << 208) divided by
+/// difficulty, rounded down. Difficulties too low to represent give the maximum target.
+pub fn diff_to_target(difficulty: f64) -> [u8; 32] {
+	if !(difficulty > 0.0) { return [0xff; 32]; }
+	if difficulty == std::f64::INFINITY { return [0; 32]; }
+
+	// Split difficulty into odd_mantissa * 2^exp
+	let bits = difficulty.to_bits();
+	let biased_exp = ((bits >> 52) & 0x7ff) as i32;
+	let (mut mantissa, mut exp) = if biased_exp == 0 {
+		(bits & ((1 << 52) - 1), -1074)
+	} else {
+		((bits & ((1 << 52) - 1)) | (1 << 52), biased_exp - 1075)
+	};
+	while mantissa & 1 == 0 {
+		mantissa >>= 1;
+		exp += 1;
+	}
+
+	// target = (0xffff << (208 - exp)) / mantissa, computed in 384 bits (as little-endian u64s)
+	let shift = 208 - exp;
+	if shift > 300 { return [0xff; 32]; }
+	let mut numerator = [0u64; 6];
+	if shift >= 0 {
+		let shift = shift as usize;
+		numerator[shift / 64] = 0xffff << (shift % 64);
+		if shift % 64 > 48 {
+			numerator[shift / 64 + 1] = 0xffff >> (64 - shift % 64);
+		}
+	} else if shift > -16 {
+		numerator[0] = 0xffff >> (-shift);
+	}
+
+	let mut quotient = [0u64; 6];
+	let mut remainder: u128 = 0;
+	for i in (0..6).rev() {
+		let cur = (remainder << 64) | numerator[i] as u128;
+		quotient[i] = (cur / mantissa as u128) as u64;
+		remainder = cur % mantissa as u128;
+	}
+	if quotient[4] != 0 || quotient[5] != 0 { return [0xff; 32]; }
+
+	let mut target = [0; 32];
 	for i in 0..32 {
-		if target[31 - i] != 0 {
-			let exp = 8*((i as i32) - 4) + (target[31 - i].leading_zeros() as i32) - 1;
-			assert!(exp >= std::f64::MIN_EXP);
-			assert!(exp <= std::f64::MAX_EXP);
-			return f64::from_bits(((exp + 1023) as u64) << 52);
+		target[i] = (quotient[i / 8] >> (8 * (i % 8))) as u8;
+	}
+	target
+}
+
+/// Decodes a compact ("nbits") target, returning None if it is negative or overflows 256 bits
+pub fn nbits_to_target(nbits: u32) -> Option<[u8; 32]> {
+	let size = (nbits >> 24) as usize;
+	let mut word = nbits & 0x007fffff;
+	if word != 0 && (nbits & 0x00800000) != 0 { return None; }
+	if word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32)) { return None; }
+
+	let mut target = [0; 32];
+	if size <= 3 {
+		word >>= 8 * (3 - size);
+		target[0] = word as u8;
+		target[1] = (word >> 8) as u8;
+		target[2] = (word >> 16) as u8;
+	} else {
+		for i in 0..3 {
+			if size - 3 + i < 32 {
+				target[size - 3 + i] = (word >> (8 * i)) as u8;
+			}
+		}
+	}
+	Some(target)
+}
+
+/// Encodes a target in compact ("nbits") form, rounding it down to the 3 bytes of precision nbits
+/// can hold
+pub fn target_to_nbits(target: &[u8; 32]) -> u32 {
+	let mut size = match target.iter().rposition(|b| *b != 0) {
+		Some(idx) => idx + 1,
+		None => return 0,
+	};
+	let mut word: u32 = 0;
+	for i in 0..3 {
+		if size >= 1 + i {
+			word |= (target[size - 1 - i] as u32) << (16 - 8 * i);
 		}
 	}
-	return std::f64::INFINITY;
+	// The top bit of the mantissa is a sign bit, so shift it out of the way
+	if (word & 0x00800000) != 0 {
+		word >>= 8;
+		size += 1;
+	}
+	word | ((size as u32) << 24)
 }
 
 pub fn le64_to_array(u: u64) -> [u8; 8] {
@@ -66,6 +161,26 @@ pub fn bytes_to_hex(bytes: &[u8]) -> String {
 	ret
 }
 
+pub fn hex_to_vec(hex: &str) -> Option<Vec<u8>> {
+	if hex.len() % 2 != 0 { return None; }
+	let mut out = Vec::with_capacity(hex.len() / 2);
+	let mut b = 0;
+	for (idx, c) in hex.as_bytes().iter().enumerate() {
+		b <<= 4;
+		match *c {
+			b'A'...b'F' => b |= c - b'A' + 10,
+			b'a'...b'f' => b |= c - b'a' + 10,
+			b'0'...b'9' => b |= c - b'0',
+			_ => return None,
+		}
+		if (idx & 1) == 1 {
+			out.push(b);
+			b = 0;
+		}
+	}
+	Some(out)
+}
+
 #[derive(Debug)]
 pub struct HandleError;
 impl std::fmt::Display for HandleError {
@@ -105,173 +220,217 @@ mod tests {
 	}
 
 	#[test]
-	fn test_target_lower_bound() {
+	fn test_diff_to_target() {
+		let mut target = [0; 32];
+		hex_to_u256("0000000000000000000000000000000000000000000000000000ffff00000000", &mut target);
+		assert_eq!(utils::diff_to_target(1.0), target);
+		hex_to_u256("0000000000000000000000000000000000000000000000000080ff7f00000000", &mut target);
+		assert_eq!(utils::diff_to_target(2.0), target);
+		hex_to_u256("00000000000000000000000000000000000000000000000000aaaa0000000000", &mut target);
+		assert_eq!(utils::diff_to_target(256.0 * 1.5), target);
+		hex_to_u256("0000000000000000000000000000000000000000000000000000feff01000000", &mut target);
+		assert_eq!(utils::diff_to_target(0.5), target);
+		// Values which don't divide evenly are rounded down
+		hex_to_u256("9224499224499224499224499224499224499224499224499224922400000000", &mut target);
+		assert_eq!(utils::diff_to_target(7.0), target);
+		hex_to_u256("ceaac0d6e6ce77a4e216537068bb0b11b8b10ccafb115d74d145b9e800000000", &mut target);
+		assert_eq!(utils::diff_to_target(1.1), target);
+		hex_to_u256("fa1ebb47b57aafda4838f03730aa125700000000a38955000000000000000000", &mut target);
+		assert_eq!(utils::diff_to_target(3290605988755.001), target);
+
+		assert_eq!(utils::diff_to_target(0.0), [0xff; 32]);
+		assert_eq!(utils::diff_to_target(1e-80), [0xff; 32]);
+		assert_eq!(utils::diff_to_target(std::f64::INFINITY), [0; 32]);
+	}
+
+	fn assert_diff_eq(diff: f64, expected: f64) {
+		assert!((diff - expected).abs() <= expected * 1e-15, "{} != {}", diff, expected);
+	}
+
+	#[test]
+	fn test_nbits() {
+		// Genesis, block 100000, block 300000 and block 840000
+		let mut target = [0; 32];
+		hex_to_u256("0000000000000000000000000000000000000000000000000000ffff00000000", &mut target);
+		assert_eq!(utils::nbits_to_target(0x1d00ffff), Some(target));
+		assert_eq!(utils::target_to_nbits(&target), 0x1d00ffff);
+		assert_eq!(utils::target_to_diff(&target), 1.0);
+		hex_to_u256("0000000000000000000000000000000000000000000000004c86040000000000", &mut target);
+		assert_eq!(utils::nbits_to_target(0x1b04864c), Some(target));
+		assert_eq!(utils::target_to_nbits(&target), 0x1b04864c);
+		assert_diff_eq(utils::target_to_diff(&target), 14484.162361225399);
+		hex_to_u256("0000000000000000000000000000000000000000000000f2b944000000000000", &mut target);
+		assert_eq!(utils::nbits_to_target(0x1a44b9f2), Some(target));
+		assert_eq!(utils::target_to_nbits(&target), 0x1a44b9f2);
+		assert_diff_eq(utils::target_to_diff(&target), 244112.48777433642);
+		hex_to_u256("0000000000000000000000000000000000000000194203000000000000000000", &mut target);
+		assert_eq!(utils::nbits_to_target(0x17034219), Some(target));
+		assert_eq!(utils::target_to_nbits(&target), 0x17034219);
+		assert_diff_eq(utils::target_to_diff(&target), 86388558925171.02);
+
+		// Encoding rounds down, and avoids setting the sign bit
+		hex_to_u256("0000000000000000000000000000000000000000000000ffffff000000000000", &mut target);
+		assert_eq!(utils::target_to_nbits(&target), 0x1b00ffff);
+		assert_eq!(utils::nbits_to_target(0x1b00ffff).unwrap()[23..27], [0, 0xff, 0xff, 0]);
+		hex_to_u256("0000000000000000000000000000000000000000000000000080000000000000", &mut target);
+		assert_eq!(utils::target_to_nbits(&target), 0x1b008000);
+		assert_eq!(utils::nbits_to_target(0x1b008000), Some(target));
+		hex_to_u256("1200000000000000000000000000000000000000000000000000000000000000", &mut target);
+		assert_eq!(utils::target_to_nbits(&target), 0x01120000);
+		assert_eq!(utils::nbits_to_target(0x01120000), Some(target));
+		assert_eq!(utils::target_to_nbits(&[0; 32]), 0);
+		assert_eq!(utils::nbits_to_target(0), Some([0; 32]));
+
+		// Negative and overflowing targets
+		assert_eq!(utils::nbits_to_target(0x1d80ffff), None);
+		assert_eq!(utils::nbits_to_target(0x2200ffff), None);
+		assert_eq!(utils::nbits_to_target(0x207fffff), Some({ let mut t = [0; 32]; t[29] = 0xff; t[30] = 0xff; t[31] = 0x7f; t }));
+	}
+
+	#[test]
+	fn test_target_to_diff() {
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000000000000ffff00000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 1.0);
-			assert!(utils::target_to_diff_lb(&target) >= 1.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 1.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000a38955000000000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 3290605988755.001);
-			assert!(utils::target_to_diff_lb(&target) >= 3290605988755.001 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 3290605988755.001);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("ffffffffffffffffffffffffffffffffffffffffffffffffffff000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 65535.0);
-			assert!(utils::target_to_diff_lb(&target) >= 65535.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 65535.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("ffffffffffffffffffffffffffffffffffffffffffffffffff00000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 16776960.0);
-			assert!(utils::target_to_diff_lb(&target) >= 16776960.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 16776960.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("ffffffffffffffffffffffffffffffffffffffffffffffff0000000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 4294901760.0);
-			assert!(utils::target_to_diff_lb(&target) >= 4294901760.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 4294901760.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("ffffffffffffffffffffffffffffffffffffffffffffff000000000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 1099494850560.0);
-			assert!(utils::target_to_diff_lb(&target) >= 1099494850560.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 1099494850560.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("ffffffffffffffffffffffffffffffffffffffffffff00000000000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 281470681743360.0);
-			assert!(utils::target_to_diff_lb(&target) >= 281470681743360.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 281470681743360.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("ffffffffffffffffffffffffffffffffffffffffff0000000000000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 72056494526300160.0);
-			assert!(utils::target_to_diff_lb(&target) >= 72056494526300160.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 72056494526300160.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("ffffffffffffffffffffffffffffffffffffffff000000000000000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 18446462598732840000.0);
-			assert!(utils::target_to_diff_lb(&target) >= 18446462598732840000.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 18446462598732840000.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000010000000000000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 18446462598732840000.0);
-			assert!(utils::target_to_diff_lb(&target) >= 18446462598732840000.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 18446462598732840000.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000100000000000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 72056494526300160.0);
-			assert!(utils::target_to_diff_lb(&target) >= 72056494526300160.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 72056494526300160.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000001000000000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 281470681743360.0);
-			assert!(utils::target_to_diff_lb(&target) >= 281470681743360.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 281470681743360.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000000010000000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 1099494850560.0);
-			assert!(utils::target_to_diff_lb(&target) >= 1099494850560.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 1099494850560.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000000000100000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 4294901760.0);
-			assert!(utils::target_to_diff_lb(&target) >= 4294901760.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 4294901760.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000000000001000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 16776960.0);
-			assert!(utils::target_to_diff_lb(&target) >= 16776960.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 16776960.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000000000000010000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 65535.0);
-			assert!(utils::target_to_diff_lb(&target) >= 65535.0 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 65535.0);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000000000000000100000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 255.99609375);
-			assert!(utils::target_to_diff_lb(&target) >= 255.99609375 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 255.99609375);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000000000000000001000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 0.9999847412109375);
-			assert!(utils::target_to_diff_lb(&target) >= 0.9999847412109375 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 0.9999847412109375);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000000000000000000010000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 0.0039061903953552246);
-			assert!(utils::target_to_diff_lb(&target) >= 0.0039061903953552246 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 0.0039061903953552246);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000000000000000000000100", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 1.5258556231856346e-05);
-			assert!(utils::target_to_diff_lb(&target) >= 1.5258556231856346e-05 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 1.5258556231856346e-05);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000000000000000000000001", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 5.960373528068885e-08);
-			assert!(utils::target_to_diff_lb(&target) >= 5.960373528068885e-08 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 5.960373528068885e-08);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("00000000000000000000000000000000000000000000000000000000000000e0", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 2.6608810393164665e-10);
-			assert!(utils::target_to_diff_lb(&target) >= 2.6608810393164665e-10 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 2.6608810393164665e-10);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("000000000000000000000000000000000000000000000000ffffff0000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 255.99610900855714);
-			assert!(utils::target_to_diff_lb(&target) >= 255.99610900855714 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 255.99610900855714);
 		}
 
 		{
 			let mut target = [0; 32];
 			hex_to_u256("0000000000000000000000000000000000000000000000ffffff000000000000", &mut target);
-			assert!(utils::target_to_diff_lb(&target) <= 65535.00390619063);
-			assert!(utils::target_to_diff_lb(&target) >= 65535.00390619063 / 4.0);
+			assert_diff_eq(utils::target_to_diff(&target), 65535.00390619063);
 		}
 	}
 }
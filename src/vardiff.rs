@@ -0,0 +1,247 @@
+// Per-client variable difficulty, so that each stratum client submits shares at roughly the same
+// rate no matter its hashrate.
+
+use std::time::{Duration,Instant};
+
+#[derive(Clone, Copy)]
+pub struct VardiffConfig {
+	/// Average time between shares we aim for from each client
+	pub share_interval: Duration,
+	/// How long we count a client's shares for before retargeting
+	pub retarget_window: Duration,
+	/// Difficulty bounds. New clients start at max_difficulty, and no client is given a difficulty
+	/// above its job's share target, so by default clients start at their job's difficulty and
+	/// vardiff lowers it from there.
+	pub min_difficulty: f64,
+	pub max_difficulty: f64,
+}
+
+impl Default for VardiffConfig {
+	fn default() -> Self {
+		Self {
+			share_interval: Duration::from_secs(10),
+			retarget_window: Duration::from_secs(60),
+			min_difficulty: 1.0,
+			max_difficulty: ::std::f64::INFINITY,
+		}
+	}
+}
+
+/// We never move difficulty by more than this factor in one retarget
+const MAX_RETARGET_FACTOR: f64 = 4.0;
+
+fn duration_secs(duration: Duration) -> f64 {
+	duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+pub struct Vardiff {
+	difficulty: f64,
+	/// The least difficulty we give this client, which it may raise above config.min_difficulty
+	min_difficulty: f64,
+	window_start: Instant,
+	window_shares: u32,
+}
+
+impl Vardiff {
+	pub fn new(config: &VardiffConfig, now: Instant) -> Self {
+		Self {
+			// Starting high means a fast miner can't flood us with shares before its first retarget
+			difficulty: config.max_difficulty,
+			min_difficulty: config.min_difficulty,
+			window_start: now,
+			window_shares: 0,
+		}
+	}
+
+	pub fn get_difficulty(&self) -> f64 {
+		self.difficulty
+	}
+
+	/// Moves straight to a difficulty the client suggested, within our bounds, and restarts the
+	/// retarget window so that vardiff adjusts from there. Returns the difficulty we settled on.
+	pub fn suggest(&mut self, config: &VardiffConfig, difficulty: f64, now: Instant) -> f64 {
+		self.difficulty = difficulty.min(config.max_difficulty).max(self.min_difficulty);
+		self.window_start = now;
+		self.window_shares = 0;
+		self.difficulty
+	}
+
+	/// Raises the least difficulty we give this client to one it asked for (eg as its hardware
+	/// can't usefully go lower), within our bounds. Returns the new difficulty if it had to go up.
+	pub fn set_minimum(&mut self, config: &VardiffConfig, min_difficulty: f64) -> Option<f64> {
+		self.min_difficulty = min_difficulty.min(config.max_difficulty).max(config.min_difficulty);
+		if self.difficulty >= self.min_difficulty { return None; }
+		self.difficulty = self.min_difficulty;
+		Some(self.difficulty)
+	}
+
+	/// Counts a share which met our difficulty, returning the new difficulty if we retargeted.
+	/// max_difficulty is the most we may give the client for its current job.
+	pub fn share_accepted(&mut self, config: &VardiffConfig, max_difficulty: f64, now: Instant) -> Option<f64> {
+		self.window_shares += 1;
+		// Retarget early if the client is flooding us (eg a fast miner which suggested too low a
+		// difficulty) rather than waiting out the whole window
+		let window_expected = duration_secs(config.retarget_window) / duration_secs(config.share_interval);
+		if self.window_shares as f64 >= window_expected * MAX_RETARGET_FACTOR {
+			return self.retarget(config, max_difficulty, now);
+		}
+		self.maybe_retarget(config, max_difficulty, now)
+	}
+
+	/// Retargets if the current window has ended, returning the new difficulty if it changed. This
+	/// should also be called periodically so that clients which stop finding shares (eg as their
+	/// difficulty is too high) get retargeted.
+	pub fn maybe_retarget(&mut self, config: &VardiffConfig, max_difficulty: f64, now: Instant) -> Option<f64> {
+		if now.duration_since(self.window_start) < config.retarget_window {
+			return None;
+		}
+		self.retarget(config, max_difficulty, now)
+	}
+
+	fn retarget(&mut self, config: &VardiffConfig, max_difficulty: f64, now: Instant) -> Option<f64> {
+		let elapsed = duration_secs(now.duration_since(self.window_start));
+		let expected_shares = elapsed / duration_secs(config.share_interval);
+		let factor = if expected_shares > 0.0 {
+			(self.window_shares as f64 / expected_shares).max(1.0 / MAX_RETARGET_FACTOR).min(MAX_RETARGET_FACTOR)
+		} else { MAX_RETARGET_FACTOR };
+		self.window_start = now;
+		self.window_shares = 0;
+
+		let max_difficulty = config.max_difficulty.min(max_difficulty);
+		// Retarget from what the client is actually mining at, which the job may cap
+		let difficulty = self.difficulty.min(max_difficulty);
+		let new_difficulty = (difficulty * factor).min(max_difficulty).max(self.min_difficulty.min(max_difficulty));
+		// Ignore small changes, which would only cause churn
+		if new_difficulty > difficulty * 0.8 && new_difficulty < difficulty * 1.25 {
+			return None;
+		}
+		self.difficulty = new_difficulty;
+		Some(new_difficulty)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use vardiff::*;
+
+	use std::time::{Duration,Instant};
+
+	fn test_config() -> VardiffConfig {
+		VardiffConfig {
+			share_interval: Duration::from_secs(10),
+			retarget_window: Duration::from_secs(60),
+			min_difficulty: 16.0,
+			max_difficulty: 65536.0,
+		}
+	}
+
+	#[test]
+	fn test_vardiff_start() {
+		let config = test_config();
+		let start = Instant::now();
+		let mut vardiff = Vardiff::new(&config, start);
+		assert_eq!(vardiff.get_difficulty(), 65536.0);
+
+		// A client which finds nothing at its job's difficulty is lowered from there
+		assert_eq!(vardiff.maybe_retarget(&config, 1000.0, start + Duration::from_secs(60)), Some(250.0));
+		// ...while a fast one just stays at it
+		let mut vardiff = Vardiff::new(&config, start);
+		for _ in 0..23 {
+			assert_eq!(vardiff.share_accepted(&config, 1000.0, start + Duration::from_secs(1)), None);
+		}
+		assert_eq!(vardiff.share_accepted(&config, 1000.0, start + Duration::from_secs(1)), None);
+	}
+
+	#[test]
+	fn test_vardiff_steady() {
+		let config = test_config();
+		let start = Instant::now();
+		let mut vardiff = Vardiff::new(&config, start);
+		vardiff.difficulty = 16.0;
+
+		// Exactly one share per share_interval doesn't change anything
+		for i in 1..13 {
+			assert_eq!(vardiff.share_accepted(&config, 1e9, start + Duration::from_secs(10 * i)), None);
+		}
+		assert_eq!(vardiff.get_difficulty(), 16.0);
+	}
+
+	#[test]
+	fn test_vardiff_fast_miner() {
+		let config = test_config();
+		let start = Instant::now();
+		let mut vardiff = Vardiff::new(&config, start);
+		vardiff.difficulty = 16.0;
+
+		// 24 shares (4x a full window's worth) in 6 seconds retargets immediately, but by at most 4x
+		for i in 0..23 {
+			assert_eq!(vardiff.share_accepted(&config, 1e9, start + Duration::from_millis(250 * i)), None);
+		}
+		assert_eq!(vardiff.share_accepted(&config, 1e9, start + Duration::from_secs(6)), Some(64.0));
+
+		// ...and is capped by both the job and max_difficulty
+		for _ in 0..23 {
+			vardiff.share_accepted(&config, 100.0, start + Duration::from_secs(7));
+		}
+		assert_eq!(vardiff.share_accepted(&config, 100.0, start + Duration::from_secs(7)), Some(100.0));
+		vardiff.difficulty = 40000.0;
+		for _ in 0..23 {
+			vardiff.share_accepted(&config, 1e9, start + Duration::from_secs(8));
+		}
+		assert_eq!(vardiff.share_accepted(&config, 1e9, start + Duration::from_secs(8)), Some(65536.0));
+	}
+
+	#[test]
+	fn test_vardiff_slow_miner() {
+		let config = test_config();
+		let start = Instant::now();
+		let mut vardiff = Vardiff::new(&config, start);
+		vardiff.difficulty = 1024.0;
+
+		// Nothing happens before the window ends
+		assert_eq!(vardiff.maybe_retarget(&config, 1e9, start + Duration::from_secs(59)), None);
+		// No shares at all drops difficulty by 4x
+		assert_eq!(vardiff.maybe_retarget(&config, 1e9, start + Duration::from_secs(60)), Some(256.0));
+		// 3 shares in a window where we expected 6 halves it
+		vardiff.share_accepted(&config, 1e9, start + Duration::from_secs(70));
+		vardiff.share_accepted(&config, 1e9, start + Duration::from_secs(90));
+		assert_eq!(vardiff.share_accepted(&config, 1e9, start + Duration::from_secs(120)), Some(128.0));
+		// But never below min_difficulty
+		assert_eq!(vardiff.maybe_retarget(&config, 1e9, start + Duration::from_secs(180)), Some(32.0));
+		assert_eq!(vardiff.maybe_retarget(&config, 1e9, start + Duration::from_secs(240)), Some(16.0));
+		assert_eq!(vardiff.maybe_retarget(&config, 1e9, start + Duration::from_secs(300)), None);
+	}
+
+	#[test]
+	fn test_vardiff_suggest() {
+		let config = test_config();
+		let start = Instant::now();
+		let mut vardiff = Vardiff::new(&config, start);
+
+		assert_eq!(vardiff.suggest(&config, 1000.0, start + Duration::from_secs(50)), 1000.0);
+		assert_eq!(vardiff.suggest(&config, 1.0, start + Duration::from_secs(50)), 16.0);
+		assert_eq!(vardiff.suggest(&config, 1e12, start + Duration::from_secs(50)), 65536.0);
+		// The retarget window restarts from the suggestion
+		assert_eq!(vardiff.maybe_retarget(&config, 1e9, start + Duration::from_secs(100)), None);
+		assert_eq!(vardiff.maybe_retarget(&config, 1e9, start + Duration::from_secs(110)), Some(16384.0));
+	}
+
+	#[test]
+	fn test_vardiff_minimum() {
+		let config = test_config();
+		let start = Instant::now();
+		let mut vardiff = Vardiff::new(&config, start);
+		vardiff.difficulty = 16.0;
+
+		assert_eq!(vardiff.set_minimum(&config, 100.0), Some(100.0));
+		// Neither retargets nor suggestions go below it
+		assert_eq!(vardiff.maybe_retarget(&config, 1e9, start + Duration::from_secs(60)), None);
+		assert_eq!(vardiff.suggest(&config, 20.0, start + Duration::from_secs(60)), 100.0);
+		assert_eq!(vardiff.set_minimum(&config, 50.0), None);
+		assert_eq!(vardiff.get_difficulty(), 100.0);
+		assert_eq!(vardiff.maybe_retarget(&config, 1e9, start + Duration::from_secs(120)), Some(50.0));
+		// ...but it's still bounded by our own limits
+		assert_eq!(vardiff.set_minimum(&config, 1e12), Some(65536.0));
+		assert_eq!(vardiff.maybe_retarget(&config, 1e9, start + Duration::from_secs(180)), None);
+	}
+}